@@ -0,0 +1,42 @@
+use crate::NodeId;
+use crate::key::Key;
+use crate::score::Score;
+
+pub(crate) enum BatchOp<K: Key, S: Score> {
+    AddChild {node: NodeId, key: K, hits: u64, score: S, max_children: u32},
+    UpdateNodeAdd {node: NodeId, hits: i64, score: S::Delta},
+}
+
+pub struct WriteBatch<K: Key = u16, S: Score = u64> {
+    pub(crate) ops: Vec<BatchOp<K, S>>,
+}
+
+impl<K: Key, S: Score> WriteBatch<K, S> {
+    pub fn new() -> WriteBatch<K, S> {
+        WriteBatch {
+            ops: Vec::new(),
+        }
+    }
+
+    pub fn add_child(&mut self, node: NodeId, key: K, hits: u64, score: S, max_children: u32) {
+        self.ops.push(BatchOp::AddChild {node, key, hits, score, max_children});
+    }
+
+    pub fn update_node_add(&mut self, node: NodeId, hits: i64, score: S::Delta) {
+        self.ops.push(BatchOp::UpdateNodeAdd {node, hits, score});
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl<K: Key, S: Score> Default for WriteBatch<K, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
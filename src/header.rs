@@ -0,0 +1,123 @@
+use crate::TreeFileError;
+use crate::TreeFileError::IncompatibleFormat;
+
+pub(crate) const HEADER_LENGTH: usize = 32;
+pub(crate) const FORMAT_VERSION: u16 = 1;
+const MAGIC: &[u8;8] = b"RTREEMAP";
+const LITTLE_ENDIAN: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum FileKind {
+    Nodes,
+    Map,
+    Master,
+    Meta,
+}
+
+pub(crate) fn has_header(buf: &[u8]) -> bool {
+    buf.len() >= MAGIC.len() && &buf[0..MAGIC.len()] == MAGIC
+}
+
+impl FileKind {
+    fn from_u8(v: u8) -> Option<FileKind> {
+        match v {
+            0 => Some(FileKind::Nodes),
+            1 => Some(FileKind::Map),
+            2 => Some(FileKind::Master),
+            3 => Some(FileKind::Meta),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            FileKind::Nodes => 0,
+            FileKind::Map => 1,
+            FileKind::Master => 2,
+            FileKind::Meta => 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Header {
+    pub(crate) file_kind: FileKind,
+    pub(crate) node_length: u32,
+    pub(crate) map_length: u32,
+    pub(crate) key_width: u16,
+    pub(crate) payload_length: u32,
+    pub(crate) score_kind: u8,
+    pub(crate) max_top_children: u32,
+}
+
+impl Header {
+    pub(crate) fn to_buf(self) -> [u8;HEADER_LENGTH] {
+        // |magic 8|version 2|file kind 1|endianness 1|node length 4|map length 4|key width 2|payload length 4|
+        // score kind 1|max top children 4|reserved 1|
+        let mut buf = [0u8;HEADER_LENGTH];
+        buf[0..8].copy_from_slice(MAGIC);
+        buf[8..10].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+        buf[10] = self.file_kind.to_u8();
+        buf[11] = LITTLE_ENDIAN;
+        buf[12..16].copy_from_slice(&self.node_length.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.map_length.to_le_bytes());
+        buf[20..22].copy_from_slice(&self.key_width.to_le_bytes());
+        buf[22..26].copy_from_slice(&self.payload_length.to_le_bytes());
+        buf[26] = self.score_kind;
+        buf[27..31].copy_from_slice(&self.max_top_children.to_le_bytes());
+
+        buf
+    }
+
+    pub(crate) fn from_buf(buf: &[u8]) -> Result<Header, TreeFileError> {
+        if buf.len() < HEADER_LENGTH || !has_header(buf) {
+            return Err(IncompatibleFormat {msg: String::from("file has no tree map header")});
+        }
+
+        let version = u16::from_le_bytes(buf[8..10].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(IncompatibleFormat {
+                msg: format!("file has format version {}, expected version {}", version, FORMAT_VERSION)
+            });
+        }
+        if buf[11] != LITTLE_ENDIAN {
+            return Err(IncompatibleFormat {msg: String::from("file is not written in little endian byte order")});
+        }
+
+        Ok(Header {
+            file_kind: FileKind::from_u8(buf[10]).ok_or(IncompatibleFormat {
+                msg: format!("unknown file kind {}", buf[10])
+            })?,
+            node_length: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+            map_length: u32::from_le_bytes(buf[16..20].try_into().unwrap()),
+            key_width: u16::from_le_bytes(buf[20..22].try_into().unwrap()),
+            payload_length: u32::from_le_bytes(buf[22..26].try_into().unwrap()),
+            score_kind: buf[26],
+            max_top_children: u32::from_le_bytes(buf[27..31].try_into().unwrap()),
+        })
+    }
+
+    pub(crate) fn check(&self, expected: &Header) -> Result<(), TreeFileError> {
+        // The max top children is only a record of how the tree was created and is not compared
+        let mismatch = if self.file_kind != expected.file_kind {
+            Some(format!("file kind {:?}, expected {:?}", self.file_kind, expected.file_kind))
+        } else if self.node_length != expected.node_length {
+            Some(format!("node record length {}, expected {}", self.node_length, expected.node_length))
+        } else if self.map_length != expected.map_length {
+            Some(format!("map record length {}, expected {}", self.map_length, expected.map_length))
+        } else if self.key_width != expected.key_width {
+            Some(format!("key width {}, expected {}", self.key_width, expected.key_width))
+        } else if self.payload_length != expected.payload_length {
+            Some(format!("payload length {}, expected {}", self.payload_length, expected.payload_length))
+        } else if self.score_kind != expected.score_kind {
+            Some(format!("score type {}, expected {}", self.score_kind, expected.score_kind))
+        } else {
+            None
+        };
+
+        match mismatch {
+            Some(msg) => Err(IncompatibleFormat {msg: format!("file has {}", msg)}),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,29 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+
+pub trait Key: Copy + Ord + Hash + Debug {
+    const WIDTH: usize;
+
+    fn to_le_buf(self, buf: &mut [u8]);
+    fn from_le_buf(buf: &[u8]) -> Self;
+}
+
+macro_rules! impl_key {
+    ($($t:ty),*) => {
+        $(
+            impl Key for $t {
+                const WIDTH: usize = std::mem::size_of::<$t>();
+
+                fn to_le_buf(self, buf: &mut [u8]) {
+                    buf[..Self::WIDTH].copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn from_le_buf(buf: &[u8]) -> Self {
+                    <$t>::from_le_bytes(buf[..Self::WIDTH].try_into().unwrap())
+                }
+            }
+        )*
+    };
+}
+
+impl_key!(u8, u16, u32, u64);
@@ -1,22 +1,39 @@
 use std::fmt::{Display, Formatter};
+use crate::key::Key;
 
+pub mod batch;
+mod header;
+pub mod key;
+pub mod migration;
 pub mod multi_file_tree_map;
+pub mod payload;
+pub mod policy;
+pub mod score;
+pub mod storage;
+pub mod traversal;
 pub mod tree_map;
 mod utils;
+pub mod verify;
+mod wal;
 
 pub type NodeId = usize;
 
-pub struct NodeData {
+pub struct NodeData<P = (), S = u64> {
     pub node_id: NodeId,
     node_pos: u64,
     pub parent: Option<NodeId>,
     pub hits: u64,
-    pub score: u64,
+    pub score: S,
     first_child_pos: u64,
     pub n_children: u32,
     pub max_children: u32,
+    children_sorted: bool,
+    pub payload: P,
 }
 
+// A child's key in its parent's child map together with the child's node data
+pub type ChildData<K, P = (), S = u64> = (K, NodeData<P, S>);
+
 #[derive(Clone)]
 pub enum OpenMode {
     TruncateCreate,
@@ -24,12 +41,12 @@ pub enum OpenMode {
     MustExist,
 }
 
-pub struct Iter {
-    key_vals: Vec<(u16, NodeId)>,
+pub struct Iter<K: Key = u16> {
+    key_vals: Vec<(K, NodeId)>,
 }
 
-impl Iterator for Iter {
-    type Item = (u16, NodeId);
+impl<K: Key> Iterator for Iter<K> {
+    type Item = (K, NodeId);
     fn next(&mut self) -> Option<Self::Item> {
         self.key_vals.pop()
     }
@@ -41,6 +58,7 @@ pub enum TreeFileError {
     NonExistingNode,
     LogicError {msg: String},
     FileIOError {msg: String},
+    IncompatibleFormat {msg: String},
 }
 
 impl Display for TreeFileError {
@@ -58,6 +76,9 @@ impl Display for TreeFileError {
             TreeFileError::FileIOError {msg} => {
                 write!(f, "FileIOError: {}", msg)
             },
+            TreeFileError::IncompatibleFormat {msg} => {
+                write!(f, "IncompatibleFormat: {}", msg)
+            },
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,257 @@
+use std::fs::{rename, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use crate::TreeFileError;
+use crate::TreeFileError::{FileIOError, IncompatibleFormat, LogicError, NonExistingFiles};
+use crate::header::{has_header, FileKind, Header, HEADER_LENGTH};
+use crate::multi_file_tree_map::{master_file_path, MASTER_MIN_LENGTH};
+use crate::tree_map::{tree_file_paths, tree_header, CHILDREN_SORTED_FLAG, FREE_NODE_POS, META_CLASS_LENGTH,
+                      META_MIN_LENGTH, NODE_CHILD_META_OFFSET, NO_FREE_POS, NO_PARENT_POS};
+use crate::utils::{create_file, open_file};
+
+// Version 0.3 files have no header, u16 keys, u64 scores and no payload
+const LEGACY_NODE_LENGTH: usize = 40;
+const LEGACY_MAP_LENGTH: usize = 10;
+const MIGRATION_CHUNK_RECORDS: usize = 4096;
+
+pub fn is_legacy_tree_map(path: &str, file_prefix: Option<u8>) -> Result<bool, TreeFileError> {
+    let (node_path, map_path, _) = tree_file_paths(path, file_prefix);
+    if !Path::new(&node_path).is_file() || !Path::new(&map_path).is_file() {
+        return Err(NonExistingFiles);
+    }
+
+    // After an interrupted migration in place either file can still be in the legacy format
+    Ok(is_legacy_file(&node_path)? || is_legacy_file(&map_path)?)
+}
+
+pub fn migrate_tree_map(path: &str, file_prefix: Option<u8>, dest_path: Option<&str>) -> Result<bool, TreeFileError> {
+    // Files are written next to their destination and renamed into place once complete, the node file
+    // last. Only files still in the legacy format are migrated, so an interrupted migration in place
+    // can be rerun
+    if !is_legacy_tree_map(path, file_prefix)? {
+        return Ok(false);
+    }
+
+    let (node_path, map_path, meta_path) = tree_file_paths(path, file_prefix);
+    let dest_paths = tree_file_paths(dest_path.unwrap_or(path), file_prefix);
+    let tmp_paths = (format!("{}.tmp", dest_paths.0), format!("{}.tmp", dest_paths.1), format!("{}.tmp", dest_paths.2));
+    let legacy_node = is_legacy_file(&node_path)?;
+    let legacy_map = is_legacy_file(&map_path)?;
+    if !legacy_node && dest_paths.0 != node_path {
+        return Err(LogicError {msg: String::from("partly migrated tree files can only be migrated in place")});
+    }
+
+    let node_file = open_file(&node_path)?;
+    let map_file = open_file(&map_path)?;
+    let node_file_length = file_length(&node_file, "node")?;
+    let map_file_length = file_length(&map_file, "map")?;
+    if (legacy_node && node_file_length % LEGACY_NODE_LENGTH as u64 != 0) ||
+        (legacy_map && map_file_length % LEGACY_MAP_LENGTH as u64 != 0) {
+        return Err(IncompatibleFormat {msg: String::from("legacy tree files end with a partial record")});
+    }
+
+    let mut node_reader = BufReader::new(node_file);
+    let mut top = [0u8;LEGACY_NODE_LENGTH];
+    node_reader.read_exact(&mut top).map_err(|e| FileIOError {
+        msg: format!("while reading from node file: {}", e)
+    })?;
+    let max_top_children = match legacy_node {
+        true => u32::from_le_bytes(top[36..40].try_into().unwrap()),
+        false => Header::from_buf(&top)?.max_top_children,
+    };
+
+    if legacy_node {
+        let header = tree_header::<u16, (), u64>(FileKind::Nodes, max_top_children);
+        let mut node_writer = BufWriter::new(create_file(&tmp_paths.0)?);
+        write_buf(&mut node_writer, &header.to_buf(), "node")?;
+        shift_node_record(&mut top);
+        write_buf(&mut node_writer, &top, "node")?;
+        copy_records(&mut node_reader, &mut node_writer, LEGACY_NODE_LENGTH, "node", shift_node_record)?;
+        flush(node_writer, "node")?;
+    }
+
+    if legacy_map {
+        let mut map_writer = BufWriter::new(create_file(&tmp_paths.1)?);
+        write_buf(&mut map_writer, &tree_header::<u16, (), u64>(FileKind::Map, max_top_children).to_buf(), "map")?;
+        copy_records(&mut BufReader::new(map_file), &mut map_writer, LEGACY_MAP_LENGTH, "map", shift_map_record)?;
+        flush(map_writer, "map")?;
+        rename_file(&tmp_paths.1, &dest_paths.1)?;
+    }
+
+    // Without free lists in the meta file they are rebuilt when the tree is opened
+    let mut meta_buf: Vec<u8> = Vec::new();
+    if Path::new(&meta_path).is_file() {
+        open_file(&meta_path)?.read_to_end(&mut meta_buf).map_err(|e| FileIOError {
+            msg: format!("while reading from meta file: {}", e)
+        })?;
+    }
+    if !has_header(&meta_buf) {
+        shift_meta(&mut meta_buf)?;
+        let mut buf = tree_header::<u16, (), u64>(FileKind::Meta, max_top_children).to_buf().to_vec();
+        buf.extend(&meta_buf);
+        create_file(&tmp_paths.2)?.write_all(&buf).map_err(|e| FileIOError {
+            msg: format!("while writing to meta file: {}", e)
+        })?;
+        rename_file(&tmp_paths.2, &dest_paths.2)?;
+    }
+
+    if legacy_node {
+        rename_file(&tmp_paths.0, &dest_paths.0)?;
+    }
+
+    Ok(true)
+}
+
+pub fn migrate_multi_file_tree_map(path: &str, dest_path: Option<&str>) -> Result<bool, TreeFileError> {
+    // The selector trees are migrated before the master file, so an interrupted migration can be rerun
+    let master_path = master_file_path(path);
+    if !Path::new(&master_path).is_file() {
+        return Err(NonExistingFiles);
+    }
+    if !is_legacy_file(&master_path)? {
+        return Ok(false);
+    }
+
+    let mut buf: Vec<u8> = Vec::new();
+    open_file(&master_path)?.read_to_end(&mut buf).map_err(|e| FileIOError {
+        msg: format!("while reading from master file: {}", e)
+    })?;
+    if buf.len() < MASTER_MIN_LENGTH {
+        return Err(LogicError {msg: String::from("no master data in master file")});
+    }
+
+    let max_top_children = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    let n_children = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+    if buf.len() < MASTER_MIN_LENGTH + n_children {
+        return Err(LogicError {msg: String::from("to few trees in master file")});
+    }
+
+    for &tree_selector in &buf[MASTER_MIN_LENGTH..MASTER_MIN_LENGTH + n_children] {
+        migrate_tree_map(path, Some(tree_selector), dest_path)?;
+    }
+
+    let dest_master_path = master_file_path(dest_path.unwrap_or(path));
+    let tmp_path = format!("{}.tmp", dest_master_path);
+    let mut master_buf = tree_header::<u16, (), u64>(FileKind::Master, max_top_children).to_buf().to_vec();
+    master_buf.extend(&buf);
+    create_file(&tmp_path)?.write_all(&master_buf).map_err(|e| FileIOError {
+        msg: format!("while writing to master file: {}", e)
+    })?;
+    rename_file(&tmp_path, &dest_master_path)?;
+
+    Ok(true)
+}
+
+fn is_legacy_file(path: &str) -> Result<bool, TreeFileError> {
+    let mut buf: Vec<u8> = Vec::new();
+    open_file(path)?.take(HEADER_LENGTH as u64).read_to_end(&mut buf).map_err(|e| FileIOError {
+        msg: format!("while reading from {}: {}", path, e)
+    })?;
+
+    Ok(!buf.is_empty() && !has_header(&buf))
+}
+
+fn rename_file(from: &str, to: &str) -> Result<(), TreeFileError> {
+    rename(from, to).map_err(|e| FileIOError {
+        msg: format!("Error while renaming file {} to {}: {}", from, to, e)
+    })
+}
+
+fn file_length(file: &File, name: &str) -> Result<u64, TreeFileError> {
+    file.metadata().map(|m| m.len()).map_err(|e| FileIOError {
+        msg: format!("while reading {} file length: {}", name, e)
+    })
+}
+
+fn copy_records<F>(reader: &mut impl Read, writer: &mut impl Write, record_length: usize, name: &str, shift: F) -> Result<(), TreeFileError>
+    where F: Fn(&mut [u8])
+{
+    let mut buf = vec![0u8;record_length * MIGRATION_CHUNK_RECORDS];
+    loop {
+        let mut length: usize = 0;
+        while length < buf.len() {
+            let n = reader.read(&mut buf[length..]).map_err(|e| FileIOError {
+                msg: format!("while reading from {} file: {}", name, e)
+            })?;
+            if n == 0 {
+                break;
+            }
+            length += n;
+        }
+        if length == 0 {
+            return Ok(());
+        }
+
+        buf[..length].chunks_exact_mut(record_length).for_each(&shift);
+        write_buf(writer, &buf[..length], name)?;
+    }
+}
+
+fn write_buf(writer: &mut impl Write, buf: &[u8], name: &str) -> Result<(), TreeFileError> {
+    writer.write_all(buf).map_err(|e| FileIOError {
+        msg: format!("while writing to {} file: {}", name, e)
+    })
+}
+
+fn flush(mut writer: impl Write, name: &str) -> Result<(), TreeFileError> {
+    writer.flush().map_err(|e| FileIOError {
+        msg: format!("while writing to {} file: {}", name, e)
+    })
+}
+
+fn shift_pos(buf: &mut [u8]) {
+    let pos = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+    buf[0..8].copy_from_slice(&(pos + HEADER_LENGTH as u64).to_le_bytes());
+}
+
+fn shift_node_record(buf: &mut [u8]) {
+    // A free node links to the next free node through its first child pos, a used node only
+    // points into the map file when it has children
+    let parent_pos = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+    let offset = NODE_CHILD_META_OFFSET as usize;
+    let first_child_pos = u64::from_le_bytes(buf[offset..8+offset].try_into().unwrap());
+    let n_children = u32::from_le_bytes(buf[8+offset..12+offset].try_into().unwrap()) & !CHILDREN_SORTED_FLAG;
+
+    if parent_pos == FREE_NODE_POS {
+        if first_child_pos != NO_FREE_POS {
+            shift_pos(&mut buf[offset..]);
+        }
+        return;
+    }
+    if parent_pos != NO_PARENT_POS {
+        shift_pos(buf);
+    }
+    if n_children > 0 {
+        shift_pos(&mut buf[offset..]);
+    }
+}
+
+fn shift_map_record(buf: &mut [u8]) {
+    // Unused records are all 0xFF, the first record of a free block links to the next free block
+    if u64::from_le_bytes(buf[0..8].try_into().unwrap()) != NO_FREE_POS {
+        shift_pos(buf);
+    }
+}
+
+fn shift_meta(buf: &mut [u8]) -> Result<(), TreeFileError> {
+    if buf.len() < META_MIN_LENGTH {
+        return Ok(());
+    }
+
+    let n_classes = u32::from_le_bytes(buf[16..20].try_into().unwrap()) as usize;
+    if buf.len() < META_MIN_LENGTH + n_classes * META_CLASS_LENGTH {
+        return Err(LogicError {msg: String::from("to few free map classes in meta file")});
+    }
+
+    if u64::from_le_bytes(buf[0..8].try_into().unwrap()) != NO_FREE_POS {
+        shift_pos(buf);
+    }
+    for class_no in 0..n_classes {
+        let offset = META_MIN_LENGTH + class_no * META_CLASS_LENGTH + 4;
+        if u64::from_le_bytes(buf[offset..8+offset].try_into().unwrap()) != NO_FREE_POS {
+            shift_pos(&mut buf[offset..]);
+        }
+    }
+
+    Ok(())
+}
@@ -3,27 +3,31 @@ use std::fs::File;
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::sync::{Mutex, MutexGuard};
-use crate::{Iter, NodeData, NodeId, OpenMode, TreeFileError};
+use crate::{ChildData, Iter, NodeData, NodeId, OpenMode, TreeFileError};
+use crate::header::{FileKind, Header, HEADER_LENGTH};
+use crate::key::Key;
 use crate::TreeFileError::{FileIOError, LogicError, NonExistingFiles};
 use crate::OpenMode::{TruncateCreate, OpenCreate, MustExist};
-use crate::tree_map::TreeMap;
+use crate::policy::{is_better_child, SelectionPolicy};
+use crate::traversal::{NodeAndChildren, Traversal};
+use crate::tree_map::{tree_header, TreeMap};
 use crate::utils::{add_and_subtract, create_file, open_file};
 
-const MASTER_MIN_LENGTH: usize = 24;
+pub(crate) const MASTER_MIN_LENGTH: usize = 24;
 
-struct MasterData {
+struct MasterData<K: Key> {
     path: String,
     master_file: File,
-    trees: HashMap<u8, TreeMap>,
+    trees: HashMap<u8, TreeMap<K>>,
     max_top_children: u32,
     hits: u64,
     score: u64,
 }
 
-pub struct MultiFileTreeMap<F> 
-    where F: Fn(u16) -> u8
+pub struct MultiFileTreeMap<F, K = u16>
+    where F: Fn(K) -> u8, K: Key
 {
-    guarded: Mutex<MasterData>,
+    guarded: Mutex<MasterData<K>>,
     splitter: F,
     open_mode: OpenMode,
 }
@@ -32,7 +36,16 @@ impl<F> MultiFileTreeMap<F>
     where F: Fn(u16) -> u8
 {
     pub fn new(path: &str, max_file_splits: u32, open_mode: OpenMode, splitter: F) -> Result<MultiFileTreeMap<F>, TreeFileError> {
-        let file_path = format!("{}/multifile_treemap.bin", path);
+        MultiFileTreeMap::open(path, max_file_splits, open_mode, splitter)
+    }
+}
+
+impl<F, K> MultiFileTreeMap<F, K>
+    where F: Fn(K) -> u8, K: Key
+{
+    // Same as MultiFileTreeMap::new for trees with other key types
+    pub fn open(path: &str, max_file_splits: u32, open_mode: OpenMode, splitter: F) -> Result<MultiFileTreeMap<F, K>, TreeFileError> {
+        let file_path = master_file_path(path);
 
         let exists = Path::new(&file_path).is_file();
 
@@ -77,11 +90,15 @@ impl<F> MultiFileTreeMap<F>
         len + 1
     }
 
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
     pub fn get_node(&mut self, node: NodeId) -> Result<NodeData, TreeFileError> {
         let mut lock = self.guarded.lock().unwrap();
 
         if node == self.get_top() {
-            return Ok(self.get_top_node_data(&mut lock)?);
+            return self.get_top_node_data(&mut lock);
         }
 
         let tree_selector = self.get_selector(node, None)?;
@@ -94,7 +111,7 @@ impl<F> MultiFileTreeMap<F>
         })
     }
 
-    pub fn add_child(&mut self, node: NodeId, key: u16, hits: u64, score: u64, max_children: u32) -> Result<NodeId, TreeFileError> {
+    pub fn add_child(&mut self, node: NodeId, key: K, hits: u64, score: u64, max_children: u32) -> Result<NodeId, TreeFileError> {
         let tree_selector = self.get_selector(node, Some(key))?;
         let mut lock = self.guarded.lock().unwrap();
 
@@ -103,7 +120,75 @@ impl<F> MultiFileTreeMap<F>
         }).map(|n| selector_node_from_node(n, tree_selector))
     }
 
-    pub fn get_child(&mut self, node: NodeId, key: u16) -> Result<Option<NodeData>, TreeFileError> {
+    pub fn get_path(&mut self, keys: &[K]) -> Result<Option<NodeId>, TreeFileError> {
+        if keys.is_empty() {
+            return Ok(Some(self.get_top()));
+        }
+
+        let tree_selector = self.get_selector(self.get_top(), Some(keys[0]))?;
+        let mut lock = self.guarded.lock().unwrap();
+
+        get_tree_and_execute(&mut lock, tree_selector, |t| {
+            t.get_path(keys)
+        }).map_or_else(|e| match e {
+            NonExistingFiles => Ok(None),
+            _ => Err(e),
+        }, |n| {
+            Ok(n.map(|n| selector_node_from_node(n, tree_selector)))
+        })
+    }
+
+    pub fn get_path_prefix(&mut self, keys: &[K]) -> Result<(NodeId, usize), TreeFileError> {
+        if keys.is_empty() {
+            return Ok((self.get_top(), 0));
+        }
+
+        let tree_selector = self.get_selector(self.get_top(), Some(keys[0]))?;
+        let mut lock = self.guarded.lock().unwrap();
+
+        let res = get_tree_and_execute(&mut lock, tree_selector, |t| {
+            t.get_path_prefix(keys)
+        });
+
+        match res {
+            Ok((_, 0)) | Err(NonExistingFiles) => Ok((self.get_top(), 0)),
+            Ok((n, depth)) => Ok((selector_node_from_node(n, tree_selector), depth)),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn insert_path(&mut self, keys: &[K], hits: u64, score: u64, max_children: u32) -> Result<NodeId, TreeFileError> {
+        if keys.is_empty() {
+            return Ok(self.get_top());
+        }
+
+        let tree_selector = self.get_selector(self.get_top(), Some(keys[0]))?;
+        let mut lock = self.guarded.lock().unwrap();
+
+        // A new tree file gets max_children for its top node and must not be left behind empty
+        if max_children == 0 && !lock.trees.contains_key(&tree_selector) {
+            return Err(LogicError {msg: String::from("trying to add more children than allowed for parent")});
+        }
+
+        create_tree_and_execute(&mut lock, tree_selector, Some(max_children), self.open_mode.clone(), |t| {
+            t.insert_path(keys, hits, score, max_children)
+        }).map(|n| selector_node_from_node(n, tree_selector))
+    }
+
+    pub fn set_max_children(&mut self, node: NodeId, max_children: u32) -> Result<(), TreeFileError> {
+        if node == self.get_top() {
+            return Err(LogicError {msg: String::from("max children of the virtual top node is given by its tree files")});
+        }
+
+        let tree_selector = self.get_selector(node, None)?;
+        let mut lock = self.guarded.lock().unwrap();
+
+        get_tree_and_execute(&mut lock, tree_selector, |t| {
+            t.set_max_children(node_from_selector_node(node), max_children)
+        })
+    }
+
+    pub fn get_child(&mut self, node: NodeId, key: K) -> Result<Option<NodeData>, TreeFileError> {
         let tree_selector = self.get_selector(node, Some(key))?;
         let mut lock = self.guarded.lock().unwrap();
 
@@ -161,7 +246,39 @@ impl<F> MultiFileTreeMap<F>
         })
     }
 
-    pub fn get_child_iter(&mut self, node: NodeId) -> Iter {
+    pub fn backpropagate(&mut self, leaf: NodeId, hits: i64, score: i64, flip_sign: bool) -> Result<usize, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+
+        if leaf == self.get_top() {
+            lock.hits = add_and_subtract(lock.hits, hits)?;
+            lock.score = add_and_subtract(lock.score, score)?;
+            save_master_data(&mut lock)?;
+            return Ok(1);
+        }
+
+        // The top nodes of the tree files stand in for the virtual top node, whose counters live in
+        // the master file, so the walk in the tree file stops below them. The new top counters are
+        // checked before the tree file is written, so a failing update leaves both untouched
+        let tree_selector = self.get_selector(leaf, None)?;
+        let (top_hits, top_score) = (lock.hits, lock.score);
+        let (n_updated, top_hits, top_score) = get_tree_and_execute(&mut lock, tree_selector, |t| {
+            let mut top = (top_hits, top_score);
+            let n_updated = t.backpropagate_path(node_from_selector_node(leaf), hits, |_| score, flip_sign, false, |depth| {
+                let score = if flip_sign && depth % 2 == 1 {-score} else {score};
+                top = (add_and_subtract(top_hits, hits)?, add_and_subtract(top_score, score)?);
+                Ok(())
+            })?;
+            Ok((n_updated, top.0, top.1))
+        })?;
+
+        lock.hits = top_hits;
+        lock.score = top_score;
+        save_master_data(&mut lock)?;
+
+        Ok(n_updated + 1)
+    }
+
+    pub fn get_child_iter(&mut self, node: NodeId) -> Iter<K> {
         let mut lock = self.guarded.lock().unwrap();
 
         let mut iter = Iter {
@@ -189,10 +306,92 @@ impl<F> MultiFileTreeMap<F>
             });
         }
 
-        return iter;
+        iter
     }
 
-    fn get_selector(&self, node: NodeId, key: Option<u16>) -> Result<u8, TreeFileError> {
+    pub fn principal_variation(&mut self, start: NodeId, policy: SelectionPolicy, max_len: usize) -> Result<Vec<ChildData<K>>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let mut variation: Vec<ChildData<K>> = Vec::new();
+        if max_len == 0 {
+            return Ok(variation);
+        }
+
+        // The children of the virtual top node are spread over the tree files, so the first step
+        // compares the best top child of every tree file
+        let (tree_selector, node) = if start == self.get_top() {
+            let top_hits = lock.hits;
+            let mut n_siblings: u32 = 0;
+            for t in lock.trees.values() {
+                n_siblings += t.get_node(t.get_top())?.n_children;
+            }
+
+            let mut best: Option<(f64, u8, K, NodeData)> = None;
+            for (&tree_selector, t) in lock.trees.iter() {
+                if let Some((value, key, node_data)) = t.select_top_child(policy, top_hits, n_siblings)? {
+                    if best.as_ref().map_or(true, |&(best_value, _, best_key, _)| is_better_child(value, key, best_value, best_key)) {
+                        best = Some((value, tree_selector, key, node_data));
+                    }
+                }
+            }
+
+            match best {
+                Some((_, tree_selector, key, mut node_data)) => {
+                    let node = node_data.node_id;
+                    node_data.node_id = selector_node_from_node(node, tree_selector);
+                    variation.push((key, node_data));
+                    (tree_selector, node)
+                },
+                None => return Ok(variation),
+            }
+        } else {
+            (self.get_selector(start, None)?, node_from_selector_node(start))
+        };
+
+        let rest = get_tree_and_execute(&mut lock, tree_selector, |t| {
+            t.principal_variation(node, policy, max_len - variation.len())
+        })?;
+        variation.extend(rest.into_iter().map(|(key, mut node_data)| {
+            node_data.node_id = selector_node_from_node(node_data.node_id, tree_selector);
+            (key, node_data)
+        }));
+
+        Ok(variation)
+    }
+
+    pub fn dfs(&mut self, start: NodeId) -> Traversal<'_, K> {
+        Traversal::new(start, true, |node, with_children| self.get_node_and_children(node, with_children))
+    }
+
+    pub fn bfs(&mut self, start: NodeId) -> Traversal<'_, K> {
+        Traversal::new(start, false, |node, with_children| self.get_node_and_children(node, with_children))
+    }
+
+    fn get_node_and_children(&mut self, node: NodeId, with_children: bool) -> Result<NodeAndChildren<K, (), u64>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+
+        if node == self.get_top() {
+            let node_data = self.get_top_node_data(&mut lock)?;
+            let mut children: Vec<(K, NodeId)> = Vec::new();
+            if with_children {
+                for (&tree_selector, t) in lock.trees.iter() {
+                    t.get_children(t.get_top())?.into_iter()
+                        .for_each(|(k, n)| children.push((k, selector_node_from_node(n, tree_selector))));
+                }
+            }
+            return Ok((node_data, children));
+        }
+
+        let tree_selector = self.get_selector(node, None)?;
+
+        let (mut node_data, children) = get_tree_and_execute(&mut lock, tree_selector, |t| {
+            t.get_node_and_children(node_from_selector_node(node), with_children)
+        })?;
+        node_data.node_id = selector_node_from_node(node_data.node_id, tree_selector);
+
+        Ok((node_data, children.into_iter().map(|(k, n)| (k, selector_node_from_node(n, tree_selector))).collect()))
+    }
+
+    fn get_selector(&self, node: NodeId, key: Option<K>) -> Result<u8, TreeFileError> {
         match key {
             Some(k) if node == self.get_top() => {
                 Ok((self.splitter)(k))
@@ -209,7 +408,7 @@ impl<F> MultiFileTreeMap<F>
         }
     }
 
-    fn get_top_node_data(&self, lock: &mut MutexGuard<MasterData>) -> Result<NodeData, TreeFileError> {
+    fn get_top_node_data(&self, lock: &mut MutexGuard<MasterData<K>>) -> Result<NodeData, TreeFileError> {
         let mut n_children: u32 = 0;
         let mut max_children: u32 = 0;
 
@@ -228,12 +427,14 @@ impl<F> MultiFileTreeMap<F>
             first_child_pos: 0,
             n_children,
             max_children,
+            children_sorted: false,
+            payload: (),
         })
     }
 }
 
-fn create_tree_and_execute<F, T>(lock: &mut MutexGuard<MasterData>, tree_selector: u8, max_top_children: Option<u32>, open_mode: OpenMode, func: F) -> Result<T, TreeFileError>
-    where F: Fn(&mut TreeMap) -> Result<T, TreeFileError>
+fn create_tree_and_execute<F, T, K: Key>(lock: &mut MutexGuard<MasterData<K>>, tree_selector: u8, max_top_children: Option<u32>, open_mode: OpenMode, func: F) -> Result<T, TreeFileError>
+    where F: Fn(&mut TreeMap<K>) -> Result<T, TreeFileError>
 {
     loop {
         match lock.trees.get_mut(&tree_selector) {
@@ -247,8 +448,8 @@ fn create_tree_and_execute<F, T>(lock: &mut MutexGuard<MasterData>, tree_selecto
     }
 }
 
-fn get_tree_and_execute<F, T>(lock: &mut MutexGuard<MasterData>, tree_selector: u8, func: F) -> Result<T, TreeFileError>
-    where F: Fn(&mut TreeMap) -> Result<T, TreeFileError>
+fn get_tree_and_execute<F, T, K: Key>(lock: &mut MutexGuard<MasterData<K>>, tree_selector: u8, func: F) -> Result<T, TreeFileError>
+    where F: Fn(&mut TreeMap<K>) -> Result<T, TreeFileError>
 {
     loop {
         match lock.trees.get_mut(&tree_selector) {
@@ -262,7 +463,7 @@ fn get_tree_and_execute<F, T>(lock: &mut MutexGuard<MasterData>, tree_selector:
     }
 }
 
-fn add_tree(lock: &mut MutexGuard<MasterData>, tree_selector: u8, max_top_children: Option<u32> , open_mode: OpenMode) -> Result<(), TreeFileError> {
+fn add_tree<K: Key>(lock: &mut MutexGuard<MasterData<K>>, tree_selector: u8, max_top_children: Option<u32> , open_mode: OpenMode) -> Result<(), TreeFileError> {
 
     if lock.trees.len() >= lock.max_top_children as usize {
         return Err(LogicError {msg: String::from("trying to add more children than allowed for parent") });
@@ -270,11 +471,11 @@ fn add_tree(lock: &mut MutexGuard<MasterData>, tree_selector: u8, max_top_childr
 
     let tree = match open_mode {
         MustExist => {
-            TreeMap::new(&lock.path, 0, open_mode, Some(tree_selector))?
+            TreeMap::open(&lock.path, 0, open_mode, Some(tree_selector))?
         },
         OpenCreate | TruncateCreate => {
             if let Some(max_top_children) = max_top_children {
-                TreeMap::new(&lock.path, max_top_children, open_mode, Some(tree_selector))?
+                TreeMap::open(&lock.path, max_top_children, open_mode, Some(tree_selector))?
             } else {
                 return Err(LogicError {
                     msg: String::from("trying to possibly create new tree map without specifying max top children")
@@ -288,13 +489,24 @@ fn add_tree(lock: &mut MutexGuard<MasterData>, tree_selector: u8, max_top_childr
     save_master_data(lock)
 }
 
-fn load_master_data(lock: &mut MutexGuard<MasterData>, open_mode: OpenMode) -> Result<(), TreeFileError> {
+pub(crate) fn master_file_path(path: &str) -> String {
+    format!("{}/multifile_treemap.bin", path)
+}
+
+fn load_master_data<K: Key>(lock: &mut MutexGuard<MasterData<K>>, open_mode: OpenMode) -> Result<(), TreeFileError> {
     lock.master_file.seek(SeekFrom::Start(0)).unwrap();
     let mut buf: Vec<u8> = Vec::new();
     lock.master_file.read_to_end(&mut buf).map_err(|e| FileIOError {
-        msg: String::from(format!("while reading from master file: {}", e))
+        msg: format!("while reading from master file: {}", e)
     })?;
 
+    // An empty master file belongs to a new tree and gets its header on the first save
+    let expected = tree_header::<K, (), u64>(FileKind::Master, lock.max_top_children);
+    if !buf.is_empty() {
+        Header::from_buf(&buf)?.check(&expected)?;
+    }
+    let buf = buf.get(HEADER_LENGTH..).unwrap_or(&[]);
+
     match open_mode {
         MustExist if buf.len() < MASTER_MIN_LENGTH => {
             return Err(LogicError {msg: String::from("no master data in master file")});
@@ -312,7 +524,7 @@ fn load_master_data(lock: &mut MutexGuard<MasterData>, open_mode: OpenMode) -> R
 
                 for offset in 0..n_children as usize {
                     let tree_selector = buf[MASTER_MIN_LENGTH+offset];
-                    let tree = TreeMap::new(&lock.path, 0, open_mode.clone(), Some(tree_selector))?;
+                    let tree = TreeMap::open(&lock.path, 0, open_mode.clone(), Some(tree_selector))?;
                     let _ = lock.trees.insert(tree_selector, tree);
                 }
             }
@@ -322,8 +534,8 @@ fn load_master_data(lock: &mut MutexGuard<MasterData>, open_mode: OpenMode) -> R
     Ok(())
 }
 
-fn save_master_data(lock: &mut MutexGuard<MasterData>) -> Result<(), TreeFileError> {
-    let mut buf: Vec<u8> = Vec::new();
+fn save_master_data<K: Key>(lock: &mut MutexGuard<MasterData<K>>) -> Result<(), TreeFileError> {
+    let mut buf = tree_header::<K, (), u64>(FileKind::Master, lock.max_top_children).to_buf().to_vec();
     lock.max_top_children.to_le_bytes().iter().for_each(|v| buf.push(*v));
     (lock.trees.len() as u32).to_le_bytes().iter().for_each(|v| buf.push(*v));
     lock.hits.to_le_bytes().iter().for_each(|v| buf.push(*v));
@@ -333,7 +545,7 @@ fn save_master_data(lock: &mut MutexGuard<MasterData>) -> Result<(), TreeFileErr
 
     lock.master_file.seek(SeekFrom::Start(0)).unwrap();
     lock.master_file.write_all(&buf).map_err(|e| FileIOError {
-        msg: String::from(format!("while writing to master file: {}", e))
+        msg: format!("while writing to master file: {}", e)
     })?;
 
     Ok(())
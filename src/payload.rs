@@ -0,0 +1,19 @@
+pub trait Payload: Default {
+    const LENGTH: usize;
+
+    fn encode(&self, buf: &mut [u8]);
+    fn decode(buf: &[u8]) -> Self;
+
+    // Prior probability used by the PUCT selection policy, children without one share the prior evenly
+    fn prior(&self) -> Option<f32> {
+        None
+    }
+}
+
+impl Payload for () {
+    const LENGTH: usize = 0;
+
+    fn encode(&self, _buf: &mut [u8]) {}
+
+    fn decode(_buf: &[u8]) -> Self {}
+}
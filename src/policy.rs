@@ -0,0 +1,40 @@
+use crate::NodeData;
+use crate::key::Key;
+
+pub(crate) type ValuedSelection<K, P, S> = (f64, K, NodeData<P, S>);
+
+#[derive(Clone, Copy, Debug)]
+pub enum SelectionPolicy {
+    Uct {exploration: f64},
+    Puct {exploration: f64},
+    MaxVisits,
+    MaxMean,
+}
+
+impl SelectionPolicy {
+    pub(crate) fn value(&self, parent_hits: u64, hits: u64, score: f64, prior: f64) -> f64 {
+        let mean = if hits == 0 {0.0} else {score / hits as f64};
+
+        match self {
+            SelectionPolicy::Uct {exploration} => {
+                // Unvisited children are always tried first
+                if hits == 0 {
+                    return f64::INFINITY;
+                }
+                mean + exploration * ((parent_hits.max(1) as f64).ln() / hits as f64).sqrt()
+            },
+            SelectionPolicy::Puct {exploration} => {
+                mean + exploration * prior * (parent_hits as f64).sqrt() / (1 + hits) as f64
+            },
+            SelectionPolicy::MaxVisits => hits as f64,
+            SelectionPolicy::MaxMean => {
+                if hits == 0 {f64::NEG_INFINITY} else {mean}
+            },
+        }
+    }
+}
+
+pub(crate) fn is_better_child<K: Key>(value: f64, key: K, best_value: f64, best_key: K) -> bool {
+    // Ties go to the lowest key
+    value > best_value || (value == best_value && key < best_key)
+}
@@ -0,0 +1,78 @@
+use std::fmt::Debug;
+use std::ops::Neg;
+use crate::TreeFileError;
+use crate::utils::add_and_subtract;
+
+pub trait Score: Copy + Default + PartialEq + Debug {
+    // Recorded in the tree's meta file so a tree is not opened with another score type than it was written with
+    const KIND: u8;
+    type Delta: Copy + Neg<Output = Self::Delta>;
+
+    fn to_le_buf(self, buf: &mut [u8]);
+    fn from_le_buf(buf: &[u8]) -> Self;
+    fn accumulate(self, delta: Self::Delta) -> Result<Self, TreeFileError>;
+    fn to_f64(self) -> f64;
+}
+
+impl Score for u64 {
+    const KIND: u8 = 0;
+    type Delta = i64;
+
+    fn to_le_buf(self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.to_le_bytes());
+    }
+
+    fn from_le_buf(buf: &[u8]) -> Self {
+        u64::from_le_bytes(buf[..8].try_into().unwrap())
+    }
+
+    fn accumulate(self, delta: i64) -> Result<Self, TreeFileError> {
+        add_and_subtract(self, delta)
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Score for i64 {
+    const KIND: u8 = 1;
+    type Delta = i64;
+
+    fn to_le_buf(self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.to_le_bytes());
+    }
+
+    fn from_le_buf(buf: &[u8]) -> Self {
+        i64::from_le_bytes(buf[..8].try_into().unwrap())
+    }
+
+    fn accumulate(self, delta: i64) -> Result<Self, TreeFileError> {
+        Ok(self.saturating_add(delta))
+    }
+
+    fn to_f64(self) -> f64 {
+        self as f64
+    }
+}
+
+impl Score for f64 {
+    const KIND: u8 = 2;
+    type Delta = f64;
+
+    fn to_le_buf(self, buf: &mut [u8]) {
+        buf[..8].copy_from_slice(&self.to_le_bytes());
+    }
+
+    fn from_le_buf(buf: &[u8]) -> Self {
+        f64::from_le_bytes(buf[..8].try_into().unwrap())
+    }
+
+    fn accumulate(self, delta: f64) -> Result<Self, TreeFileError> {
+        Ok(self + delta)
+    }
+
+    fn to_f64(self) -> f64 {
+        self
+    }
+}
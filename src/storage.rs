@@ -0,0 +1,282 @@
+use std::fs::{rename, File};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use memmap2::{MmapMut, MmapOptions};
+
+const WRITER_BUFFER_LENGTH: usize = 64 * 1024;
+const COPY_CHUNK_LENGTH: usize = 64 * 1024;
+const MMAP_RESERVE_LENGTH: u64 = 1024 * 1024;
+
+// Backing store for one tree file. Positions are byte offsets and writes past the end grow the storage
+pub trait Storage: Sized {
+    // Only storages that outlive the tree can use a write ahead log
+    const PERSISTENT: bool = true;
+
+    fn open(path: &str, create: bool) -> std::io::Result<Self>;
+    fn exists(path: &str) -> bool;
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> std::io::Result<()>;
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> std::io::Result<()>;
+    fn len(&mut self) -> std::io::Result<u64>;
+    fn set_len(&mut self, len: u64) -> std::io::Result<()>;
+    fn sync(&mut self) -> std::io::Result<()>;
+
+    fn is_empty(&mut self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    // Replaces the storage at `to` with this one, which was opened at `from`
+    fn move_to(self, from: &str, to: &str) -> std::io::Result<Self> {
+        drop(self);
+        rename(from, to)?;
+        Self::open(to, false)
+    }
+}
+
+pub struct FileStorage {
+    file: File,
+}
+
+pub struct MmapStorage {
+    file: File,
+    mmap: Option<MmapMut>,
+    len: u64,
+}
+
+#[derive(Default)]
+pub struct MemStorage {
+    data: Vec<u8>,
+}
+
+impl Storage for FileStorage {
+    fn open(path: &str, create: bool) -> std::io::Result<Self> {
+        Ok(FileStorage { file: open_or_create(path, create)? })
+    }
+
+    fn exists(path: &str) -> bool {
+        Path::new(path).is_file()
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.file.read_exact(buf)
+    }
+
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> std::io::Result<()> {
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.file.write_all(buf)
+    }
+
+    fn len(&mut self) -> std::io::Result<u64> {
+        self.file.seek(SeekFrom::End(0))
+    }
+
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(len)
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Storage for MmapStorage {
+    fn open(path: &str, create: bool) -> std::io::Result<Self> {
+        let file = open_or_create(path, create)?;
+        let len = file.metadata()?.len();
+        Ok(MmapStorage { file, mmap: None, len })
+    }
+
+    fn exists(path: &str) -> bool {
+        Path::new(path).is_file()
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = pos + buf.len() as u64;
+        if end > self.len {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "read past the end of the file"));
+        }
+        if end > self.mapped_len() {
+            self.remap(end)?;
+        }
+
+        let mmap = self.mmap.as_ref().unwrap();
+        buf.copy_from_slice(&mmap[pos as usize..end as usize]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> std::io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let end = pos + buf.len() as u64;
+        if end > self.len {
+            // The file always ends at the stored length, only the mapping is reserved ahead of it
+            self.file.set_len(end)?;
+            self.len = end;
+        }
+        if end > self.mapped_len() {
+            self.remap(end)?;
+        }
+
+        let mmap = self.mmap.as_mut().unwrap();
+        mmap[pos as usize..end as usize].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&mut self) -> std::io::Result<u64> {
+        Ok(self.len)
+    }
+
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.file.set_len(len)?;
+        self.len = len;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        if let Some(mmap) = self.mmap.as_ref() {
+            mmap.flush()?;
+        }
+        self.file.sync_data()
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.mmap.as_ref().map_or(Ok(()), |mmap| mmap.flush_async())
+    }
+}
+
+impl MmapStorage {
+    fn mapped_len(&self) -> u64 {
+        self.mmap.as_ref().map_or(0, |mmap| mmap.len() as u64)
+    }
+
+    fn remap(&mut self, end: u64) -> std::io::Result<()> {
+        // The mapping grows in steps, so appends do not map the file again for every record
+        let mapped_len = end.max(self.mapped_len() + self.mapped_len() / 2).max(MMAP_RESERVE_LENGTH);
+        self.mmap = None;
+        // SAFETY: the tree files are only modified through this mapping while the tree holds its lock.
+        // The mapping can reach past the end of the file, but only the part up to the stored length,
+        // which is the file length, is accessed
+        self.mmap = Some(unsafe { MmapOptions::new().len(mapped_len as usize).map_mut(&self.file)? });
+        Ok(())
+    }
+}
+
+impl Storage for MemStorage {
+    const PERSISTENT: bool = false;
+
+    fn open(path: &str, create: bool) -> std::io::Result<Self> {
+        if create {
+            Ok(MemStorage::default())
+        } else {
+            Err(Error::new(ErrorKind::NotFound, format!("no in memory storage at {}", path)))
+        }
+    }
+
+    fn exists(_path: &str) -> bool {
+        false
+    }
+
+    fn read_at(&mut self, pos: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let start = pos as usize;
+        let src = self.data.get(start..start + buf.len()).ok_or(Error::new(ErrorKind::UnexpectedEof, "read past the end of the storage"))?;
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write_at(&mut self, pos: u64, buf: &[u8]) -> std::io::Result<()> {
+        let start = pos as usize;
+        if self.data.len() < start + buf.len() {
+            self.data.resize(start + buf.len(), 0);
+        }
+        self.data[start..start + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn len(&mut self) -> std::io::Result<u64> {
+        Ok(self.data.len() as u64)
+    }
+
+    fn set_len(&mut self, len: u64) -> std::io::Result<()> {
+        self.data.resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn move_to(self, _from: &str, _to: &str) -> std::io::Result<Self> {
+        Ok(self)
+    }
+}
+
+impl MemStorage {
+    pub(crate) fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+// Writes a whole storage front to back through a buffer
+pub(crate) struct StorageWriter<'a, St: Storage> {
+    storage: &'a mut St,
+    pos: u64,
+    buf: Vec<u8>,
+}
+
+impl<'a, St: Storage> StorageWriter<'a, St> {
+    pub(crate) fn new(storage: &'a mut St) -> StorageWriter<'a, St> {
+        StorageWriter { storage, pos: 0, buf: Vec::new() }
+    }
+
+    pub(crate) fn write_all(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= WRITER_BUFFER_LENGTH {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> std::io::Result<()> {
+        self.storage.write_at(self.pos, &self.buf)?;
+        self.pos += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+pub(crate) fn copy_storage<From: Storage, To: Storage>(from: &mut From, to: &mut To) -> std::io::Result<()> {
+    let len = from.len()?;
+    let mut writer = StorageWriter::new(to);
+    let mut buf = vec![0u8;COPY_CHUNK_LENGTH];
+    let mut pos: u64 = 0;
+    while pos < len {
+        let n = COPY_CHUNK_LENGTH.min((len - pos) as usize);
+        from.read_at(pos, &mut buf[..n])?;
+        writer.write_all(&buf[..n])?;
+        pos += n as u64;
+    }
+    writer.flush()?;
+
+    to.set_len(len)
+}
+
+fn open_or_create(path: &str, create: bool) -> std::io::Result<File> {
+    File::options()
+        .create(create)
+        .truncate(create)
+        .write(true)
+        .read(true)
+        .open(path)
+}
@@ -0,0 +1,82 @@
+use std::collections::VecDeque;
+use crate::{NodeData, NodeId, TreeFileError};
+use crate::key::Key;
+
+pub(crate) type NodeAndChildren<K, P, S> = (NodeData<P, S>, Vec<(K, NodeId)>);
+type ExpandFn<'a, K, P, S> = dyn FnMut(NodeId, bool) -> Result<NodeAndChildren<K, P, S>, TreeFileError> + 'a;
+type SkipFn<'a, P, S> = dyn Fn(&NodeData<P, S>) -> bool + 'a;
+
+pub struct Traversal<'a, K: Key = u16, P = (), S = u64> {
+    expand: Box<ExpandFn<'a, K, P, S>>,
+    frontier: VecDeque<(usize, Option<K>, NodeId)>,
+    depth_first: bool,
+    max_depth: Option<usize>,
+    skip_subtree: Option<Box<SkipFn<'a, P, S>>>,
+}
+
+impl<'a, K: Key, P, S> Traversal<'a, K, P, S> {
+    pub(crate) fn new<F>(start: NodeId, depth_first: bool, expand: F) -> Traversal<'a, K, P, S>
+        where F: FnMut(NodeId, bool) -> Result<NodeAndChildren<K, P, S>, TreeFileError> + 'a
+    {
+        Traversal {
+            expand: Box::new(expand),
+            frontier: VecDeque::from([(0, None, start)]),
+            depth_first,
+            max_depth: None,
+            skip_subtree: None,
+        }
+    }
+
+    pub fn max_depth(mut self, max_depth: usize) -> Traversal<'a, K, P, S> {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn skip_subtree<F>(mut self, skip_subtree: F) -> Traversal<'a, K, P, S>
+        where F: Fn(&NodeData<P, S>) -> bool + 'a
+    {
+        self.skip_subtree = Some(Box::new(skip_subtree));
+        self
+    }
+}
+
+impl<K: Key, P, S> Iterator for Traversal<'_, K, P, S> {
+    type Item = Result<(usize, Option<K>, NodeData<P, S>), TreeFileError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Only the frontier is held in memory, nodes and their child maps are read as they are visited
+        let (depth, key, node) = if self.depth_first {
+            self.frontier.pop_back()?
+        } else {
+            self.frontier.pop_front()?
+        };
+
+        let below_max_depth = match self.max_depth {
+            Some(max_depth) => depth < max_depth,
+            None => true,
+        };
+
+        match (self.expand)(node, below_max_depth) {
+            Ok((node_data, mut children)) => {
+                let skip = match self.skip_subtree {
+                    Some(ref skip_subtree) => skip_subtree(&node_data),
+                    None => false,
+                };
+
+                if below_max_depth && !skip {
+                    children.sort_unstable_by_key(|&(k, _)| k);
+                    if self.depth_first {
+                        children.reverse();
+                    }
+                    children.into_iter().for_each(|(k, n)| self.frontier.push_back((depth + 1, Some(k), n)));
+                }
+
+                Some(Ok((depth, key, node_data)))
+            },
+            Err(e) => {
+                self.frontier.clear();
+                Some(Err(e))
+            },
+        }
+    }
+}
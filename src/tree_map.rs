@@ -1,73 +1,150 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
 use std::path::Path;
 use std::sync::{Mutex, MutexGuard};
-use crate::{Iter, NodeData, NodeId, OpenMode, TreeFileError};
+use crate::{ChildData, Iter, NodeData, NodeId, OpenMode, TreeFileError};
+use crate::batch::{BatchOp, WriteBatch};
+use crate::header::{FileKind, Header, HEADER_LENGTH};
+use crate::key::Key;
+use crate::payload::Payload;
+use crate::policy::{is_better_child, SelectionPolicy, ValuedSelection};
+use crate::score::Score;
+use crate::storage::{copy_storage, FileStorage, MemStorage, Storage, StorageWriter};
+use crate::traversal::{NodeAndChildren, Traversal};
 use crate::TreeFileError::{NonExistingFiles, NonExistingNode, FileIOError, LogicError};
 use crate::OpenMode::{TruncateCreate, OpenCreate, MustExist};
+use crate::verify::Inconsistency;
 use crate::utils::{add_and_subtract, create_file, open_file};
+use crate::wal::{records_from_buf, records_to_buf, PendingWrites, TreeFile, WalRecord};
 
 
-const NODE_LENGTH: usize = 40;
-const MAP_LENGTH: usize = 10;
+const NODE_META_LENGTH: usize = 40;
+const MAP_NODE_POS_LENGTH: usize = 8;
 const NODE_CHILD_META_LENGTH: usize = 16;
-const NODE_CHILD_META_OFFSET: u64 = 24;
+pub(crate) const NODE_CHILD_META_OFFSET: u64 = 24;
+pub(crate) const NO_PARENT_POS: u64 = u64::MAX;
+pub(crate) const FREE_NODE_POS: u64 = u64::MAX - 1;
+pub(crate) const NO_FREE_POS: u64 = u64::MAX;
+pub(crate) const CHILDREN_SORTED_FLAG: u32 = 1 << 31;
+pub(crate) const META_MIN_LENGTH: usize = 20;
+pub(crate) const META_CLASS_LENGTH: usize = 12;
+const VERIFY_CHUNK_LENGTH: usize = 4096;
 
 struct ChildrenMeta {
     first_child_pos: u64,
     n_children: u32,
     max_children: u32,
+    sorted: bool,
 }
 
-struct ChildMap {
+struct ChildMap<K: Key> {
     node_id: NodeId,
     node_pos: u64,
-    key: u16,
+    key: K,
 }
 
-struct ChildrenMaps {
-    key_hit: Option<ChildMap>,
-    child_maps: Vec<ChildMap>,
+struct ChildrenMaps<K: Key> {
+    key_hit: Option<ChildMap<K>>,
+    child_maps: Vec<ChildMap<K>>,
 }
 
-struct FileData {
-    node_file: File,
-    map_file: File,
+// New node ids by old node id, and the node, map and meta storages of the copy
+type CopiedTree<St> = (HashMap<NodeId, NodeId>, (St, St, St));
+
+struct FileData<St: Storage> {
+    node_file: St,
+    map_file: St,
+    meta_file: St,
     n_nodes: usize,
+    n_free: usize,
+    free_node_head: u64,
+    free_map_heads: HashMap<u32, u64>,
+    node_length: usize,
+    header: Header,
+    wal: Option<File>,
+    pending: Option<PendingWrites>,
 }
-pub struct TreeMap {
-    guarded: Mutex<FileData>,
+
+// A tree map kept entirely in memory, it can be saved to and loaded from regular tree files
+pub type MemTreeMap<K = u16, P = (), S = u64> = TreeMap<K, P, S, MemStorage>;
+
+pub struct TreeMap<K: Key = u16, P: Payload = (), S: Score = u64, St: Storage = FileStorage> {
+    guarded: Mutex<FileData<St>>,
+    path: String,
+    file_prefix: Option<u8>,
+    key_type: PhantomData<K>,
+    payload_type: PhantomData<P>,
+    score_type: PhantomData<S>,
 }
 
 impl TreeMap {
     pub fn new(path: &str, max_top_children: u32, open_mode: OpenMode, file_prefix: Option<u8>) -> Result<TreeMap, TreeFileError> {
-        let prefix = if let Some(p) = file_prefix {format!("{:03}.", p)} else {String::new()};
-        let node_path = format!("{}/{}treemap.nodes.bin", path, prefix);
-        let map_path = format!("{}/{}treemap.map.bin", path, prefix);
+        TreeMap::open(path, max_top_children, open_mode, file_prefix)
+    }
+}
+
+impl<K: Key, P: Payload, S: Score, St: Storage> TreeMap<K, P, S, St> {
+    // Same as TreeMap::new for trees with other key, payload, score or storage types
+    pub fn open(path: &str, max_top_children: u32, open_mode: OpenMode, file_prefix: Option<u8>) -> Result<TreeMap<K, P, S, St>, TreeFileError> {
+        let (node_path, map_path, meta_path) = tree_file_paths(path, file_prefix);
 
-        let exists = Path::new(&node_path).is_file() && Path::new(&map_path).is_file();
+        let exists = St::exists(&node_path) && St::exists(&map_path);
 
-        let (node_file, map_file) = match open_mode {
-            TruncateCreate => (create_file(&node_path)?, create_file(&map_path)?),
-            OpenCreate if exists => (open_file(&node_path)?, open_file(&map_path)?),
-            OpenCreate => (create_file(&node_path)?, create_file(&map_path)?),
-            MustExist if exists => (open_file(&node_path)?, open_file(&map_path)?),
+        let create = match open_mode {
+            TruncateCreate => true,
+            OpenCreate => !exists,
+            MustExist if exists => false,
             MustExist => { return Err(NonExistingFiles) },
         };
+        let node_file = open_storage(&node_path, create)?;
+        let map_file = open_storage(&map_path, create)?;
 
+        // Tree files written before the meta file existed get a fresh one, rebuilt from the node file
+        let meta_file = open_storage(&meta_path, create || !St::exists(&meta_path))?;
+
+        TreeMap::from_storages(path, max_top_children, file_prefix, (node_file, map_file, meta_file), !create)
+    }
+
+    fn from_storages(path: &str, max_top_children: u32, file_prefix: Option<u8>, storages: (St, St, St), exists: bool) -> Result<TreeMap<K, P, S, St>, TreeFileError> {
+        let (node_file, map_file, meta_file) = storages;
         let tree = TreeMap {
             guarded: Mutex::new(FileData {
                 node_file,
                 map_file,
+                meta_file,
                 n_nodes: 0,
+                n_free: 0,
+                free_node_head: NO_FREE_POS,
+                free_map_heads: HashMap::new(),
+                node_length: NODE_META_LENGTH + P::LENGTH,
+                header: tree_header::<K, P, S>(FileKind::Nodes, max_top_children),
+                wal: None,
+                pending: None,
             }),
+            path: String::from(path),
+            file_prefix,
+            key_type: PhantomData,
+            payload_type: PhantomData,
+            score_type: PhantomData,
         };
 
         {
             let mut lock = tree.guarded.lock().unwrap();
+            let wal_path = wal_file_path(path, file_prefix);
+            match exists {
+                _ if !St::PERSISTENT => (),
+                true => replay_wal(&mut lock, &wal_path)?,
+                false if Path::new(&wal_path).is_file() => { create_file(&wal_path)?; },
+                false => (),
+            }
+            check_headers(&mut lock)?;
             count_nodes(&mut lock)?;
+            load_meta_data(&mut lock)?;
             if lock.n_nodes == 0 {
-                add_node(&mut lock, u64::MAX, 0, 0, max_top_children)?;
+                add_node(&mut lock, NO_PARENT_POS, 0, S::default(), max_top_children, P::default())?;
             }
         }
 
@@ -80,131 +157,1105 @@ impl TreeMap {
 
     pub fn len(&self) -> usize {
         let lock = self.guarded.lock().unwrap();
-        lock.n_nodes
+        lock.n_nodes - lock.n_free
+    }
+
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    pub fn get_node(&self, node: NodeId) -> Result<NodeData<P, S>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let node_pos = check_presence(&mut lock, node)?;
+
+        get_node(&mut lock, node_pos)
+    }
+
+    pub fn add_child(&mut self, node: NodeId, key: K, hits: u64, score: S, max_children: u32) -> Result<NodeId, TreeFileError> {
+        self.add_child_with_payload(node, key, hits, score, max_children, P::default())
+    }
+
+    pub fn add_child_with_payload(&mut self, node: NodeId, key: K, hits: u64, score: S, max_children: u32, payload: P) -> Result<NodeId, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let parent_pos = check_presence(&mut lock, node)?;
+        begin_transaction(&mut lock);
+        let res = add_child_node(&mut lock, parent_pos, key, hits, score, max_children, payload);
+        let child_pos = end_transaction(&mut lock, res)?;
+
+        Ok(pos_to_node_id(&lock, child_pos))
+    }
+
+    pub fn get_path(&self, keys: &[K]) -> Result<Option<NodeId>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let top_pos = node_id_to_pos(&lock, self.get_top());
+
+        let (node_pos, depth) = follow_path(&mut lock, top_pos, keys)?;
+        if depth == keys.len() {
+            Ok(Some(pos_to_node_id(&lock, node_pos)))
+        } else {
+            Ok(None)
+        }
     }
 
-    pub fn get_node(&self, node: NodeId) -> Result<NodeData, TreeFileError> {
+    pub fn get_path_prefix(&self, keys: &[K]) -> Result<(NodeId, usize), TreeFileError> {
         let mut lock = self.guarded.lock().unwrap();
-        check_presence(&mut lock, node)?;
+        let top_pos = node_id_to_pos(&lock, self.get_top());
 
-        get_node(&mut lock, node_id_to_pos(node))
+        let (node_pos, depth) = follow_path(&mut lock, top_pos, keys)?;
+        Ok((pos_to_node_id(&lock, node_pos), depth))
     }
 
-    pub fn add_child(&mut self, node: NodeId, key: u16, hits: u64, score: u64, max_children: u32) -> Result<NodeId, TreeFileError> {
+    pub fn insert_path(&mut self, keys: &[K], hits: u64, score: S, max_children: u32) -> Result<NodeId, TreeFileError> {
         let mut lock = self.guarded.lock().unwrap();
-        check_presence(&mut lock, node)?;
+        let top_pos = node_id_to_pos(&lock, self.get_top());
 
-        let parent_pos = node_id_to_pos(node);
-        let child_pos = expected_node_pos(&mut lock);
+        let (node_pos, depth) = follow_path(&mut lock, top_pos, keys)?;
+        begin_transaction(&mut lock);
+        let res = keys[depth..].iter().try_fold(node_pos, |node_pos, &key| {
+            add_child_node(&mut lock, node_pos, key, hits, score, max_children, P::default())
+        });
+        let node_pos = end_transaction(&mut lock, res)?;
 
-        let mut children_meta = get_node_child_meta(&mut lock, parent_pos)?;
+        Ok(pos_to_node_id(&lock, node_pos))
+    }
 
+    pub fn remove_child(&mut self, node: NodeId, key: K) -> Result<usize, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let parent_pos = check_presence(&mut lock, node)?;
+        let children_meta = get_node_child_meta(&mut lock, parent_pos)?;
         if children_meta.n_children == 0 {
-            new_children_child_mappings(&mut lock, parent_pos, key, child_pos, &mut children_meta)?;
+            return Ok(0);
+        }
+
+        if let Some(c) = find_child_map(&mut lock, key, &children_meta)? {
+            begin_transaction(&mut lock);
+            let res = unlink_child::<K, _>(&mut lock, parent_pos, c.node_pos)
+                .and_then(|_| free_subtree::<K, _>(&mut lock, c.node_pos));
+            end_transaction(&mut lock, res)
         } else {
-            update_children_child_mappings(&mut lock, parent_pos, key, child_pos, &mut children_meta)?;
+            Ok(0)
         }
+    }
 
-        add_node(&mut lock, parent_pos, hits, score, max_children)?;
+    pub fn prune_subtree(&mut self, node: NodeId) -> Result<usize, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let node_pos = check_presence(&mut lock, node)?;
 
-        Ok(pos_to_node_id(child_pos))
+        let node_data = get_node::<(), S, _>(&mut lock, node_pos)?;
+        match node_data.parent {
+            Some(parent) => {
+                let parent_pos = node_id_to_pos(&lock, parent);
+                begin_transaction(&mut lock);
+                let res = unlink_child::<K, _>(&mut lock, parent_pos, node_data.node_pos)
+                    .and_then(|_| free_subtree::<K, _>(&mut lock, node_data.node_pos));
+                end_transaction(&mut lock, res)
+            },
+            None => Err(LogicError {
+                msg: String::from("top node can not be pruned, remove its children instead")
+            }),
+        }
     }
 
-    pub fn get_child(&self, node: NodeId, key: u16) -> Result<Option<NodeData>, TreeFileError> {
+    pub fn set_max_children(&mut self, node: NodeId, max_children: u32) -> Result<(), TreeFileError> {
         let mut lock = self.guarded.lock().unwrap();
-        check_presence(&mut lock, node)?;
+        let node_pos = check_presence(&mut lock, node)?;
+        let mut children_meta = get_node_child_meta(&mut lock, node_pos)?;
+        if max_children < children_meta.n_children {
+            return Err(LogicError {
+                msg: String::from("trying to set max children below the number of existing children")
+            });
+        }
+        if max_children == children_meta.max_children {
+            return Ok(());
+        }
 
-        let parent_pos = node_id_to_pos(node);
+        begin_transaction(&mut lock);
+        let res = match children_meta.n_children {
+            0 => Ok(()),
+            _ => relocate_children_maps::<K, _>(&mut lock, &mut children_meta, max_children),
+        }.and_then(|_| {
+            children_meta.max_children = max_children;
+            update_node_child_meta(&mut lock, node_pos, &children_meta)
+        });
+        end_transaction(&mut lock, res)
+    }
+
+    pub fn get_child(&self, node: NodeId, key: K) -> Result<Option<NodeData<P, S>>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let parent_pos = check_presence(&mut lock, node)?;
         let children_meta = get_node_child_meta(&mut lock, parent_pos)?;
         if children_meta.n_children == 0 {
             return Ok(None);
         }
 
-        let res = get_children_maps(&mut lock, Some(key), &children_meta)?;
-
-        if let Some(c) = res.key_hit {
+        if let Some(c) = find_child_map(&mut lock, key, &children_meta)? {
             Ok(Some(get_node(&mut lock, c.node_pos)?))
         } else {
             Ok(None)
         }
     }
 
-    pub fn get_parent(&self, node: NodeId) -> Result<Option<NodeData>, TreeFileError> {
+    pub fn select_child(&self, node: NodeId, policy: SelectionPolicy) -> Result<Option<ChildData<K, P, S>>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let node_pos = check_presence(&mut lock, node)?;
+        let node_data = get_node::<(), S, _>(&mut lock, node_pos)?;
+
+        Ok(best_child::<K, P, S, _>(&mut lock, node_pos, policy, node_data.hits, node_data.n_children)?
+            .map(|(_, key, child)| (key, child)))
+    }
+
+    pub fn principal_variation(&self, start: NodeId, policy: SelectionPolicy, max_len: usize) -> Result<Vec<ChildData<K, P, S>>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let mut node_pos = check_presence(&mut lock, start)?;
+        let node_data = get_node::<(), S, _>(&mut lock, node_pos)?;
+        let (mut parent_hits, mut n_children) = (node_data.hits, node_data.n_children);
+
+        let mut variation: Vec<ChildData<K, P, S>> = Vec::new();
+        while variation.len() < max_len {
+            match best_child::<K, P, S, _>(&mut lock, node_pos, policy, parent_hits, n_children)? {
+                Some((_, key, child)) => {
+                    (node_pos, parent_hits, n_children) = (child.node_pos, child.hits, child.n_children);
+                    variation.push((key, child));
+                },
+                None => break,
+            }
+        }
+
+        Ok(variation)
+    }
+
+    pub(crate) fn select_top_child(&self, policy: SelectionPolicy, parent_hits: u64, n_siblings: u32) -> Result<Option<ValuedSelection<K, P, S>>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let top_pos = node_id_to_pos(&lock, self.get_top());
+
+        best_child::<K, P, S, _>(&mut lock, top_pos, policy, parent_hits, n_siblings)
+    }
+
+    pub fn get_nodes(&self, nodes: &[NodeId]) -> Vec<Result<NodeData<P, S>, TreeFileError>> {
+        let mut lock = self.guarded.lock().unwrap();
+        let n_nodes = lock.n_nodes;
+
+        let node_positions = nodes.iter()
+            .filter(|&&node| node < n_nodes)
+            .map(|&node| node_id_to_pos(&lock, node))
+            .collect::<Vec<u64>>();
+        let mut node_data = get_nodes_at::<P, S, _>(&mut lock, &node_positions).into_iter();
+
+        nodes.iter()
+            .map(|&node| if node < n_nodes {node_data.next().unwrap()} else {Err(NonExistingNode)})
+            .collect()
+    }
+
+    pub fn get_children_data(&self, node: NodeId) -> Result<Vec<ChildData<K, P, S>>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let node_pos = check_presence(&mut lock, node)?;
+        let children_meta = get_node_child_meta(&mut lock, node_pos)?;
+        if children_meta.n_children == 0 {
+            return Ok(Vec::new());
+        }
+
+        let child_maps = get_children_maps::<K, _>(&mut lock, None, &children_meta)?.child_maps;
+        let node_positions = child_maps.iter().map(|cm| cm.node_pos).collect::<Vec<u64>>();
+
+        child_maps.iter()
+            .zip(get_nodes_at::<P, S, _>(&mut lock, &node_positions))
+            .map(|(cm, node_data)| node_data.map(|nd| (cm.key, nd)))
+            .collect()
+    }
+
+    pub fn get_parent(&self, node: NodeId) -> Result<Option<NodeData<P, S>>, TreeFileError> {
         let mut lock = self.guarded.lock().unwrap();
-        check_presence(&mut lock, node)?;
+        let node_pos = check_presence(&mut lock, node)?;
 
-        let node_data = get_node(&mut lock, node_id_to_pos(node))?;
+        let node_data = get_node::<(), S, _>(&mut lock, node_pos)?;
         match node_data.parent {
             Some(node_id) => {
-                let parent_pos = node_id_to_pos(node_id);
+                let parent_pos = node_id_to_pos(&lock, node_id);
                 Ok(Some(get_node(&mut lock, parent_pos)?))
             },
             None => Ok(None)
         }
     }
 
-    pub fn update_node_add(&self, node: NodeId, hits: i64, score: i64) -> Result<(), TreeFileError> {
+    pub fn path_to(&self, node: NodeId) -> Result<Vec<K>, TreeFileError> {
         let mut lock = self.guarded.lock().unwrap();
-        check_presence(&mut lock, node)?;
+        let mut node_pos = check_presence(&mut lock, node)?;
+
+        let mut keys: Vec<K> = Vec::new();
+        while let Some(parent) = get_node::<(), S, _>(&mut lock, node_pos)?.parent {
+            let parent_pos = node_id_to_pos(&lock, parent);
+            let children_meta = get_node_child_meta(&mut lock, parent_pos)?;
+            let key = get_children_maps::<K, _>(&mut lock, None, &children_meta)?
+                .child_maps.into_iter()
+                .find(|cm| cm.node_pos == node_pos)
+                .map(|cm| cm.key)
+                .ok_or(LogicError {
+                    msg: String::from("child node not found among the parents child maps")
+                })?;
+
+            keys.push(key);
+            node_pos = parent_pos;
+        }
+        keys.reverse();
+
+        Ok(keys)
+    }
 
-        let mut node_data = get_node(&mut lock, node_id_to_pos(node))?;
+    pub fn update_node_add(&self, node: NodeId, hits: i64, score: S::Delta) -> Result<(), TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let node_pos = check_presence(&mut lock, node)?;
+
+        let mut node_data = get_node::<P, S, _>(&mut lock, node_pos)?;
         node_data.hits = add_and_subtract(node_data.hits, hits)?;
-        node_data.score = add_and_subtract(node_data.score, score)?;
-        update_node(&mut lock, &node_data)?;
+        node_data.score = node_data.score.accumulate(score)?;
+        begin_transaction(&mut lock);
+        let res = update_node(&mut lock, &node_data);
+        end_transaction(&mut lock, res)
+    }
+
+    pub fn backpropagate<F>(&self, leaf: NodeId, hits: i64, score_fn: F, flip_sign: bool) -> Result<usize, TreeFileError>
+        where F: FnMut(&NodeData<P, S>) -> S::Delta
+    {
+        self.backpropagate_path(leaf, hits, score_fn, flip_sign, true, |_| Ok(()))
+    }
+
+    pub(crate) fn backpropagate_path<F, C>(&self, leaf: NodeId, hits: i64, mut score_fn: F, flip_sign: bool, include_top: bool, before_write: C) -> Result<usize, TreeFileError>
+        where F: FnMut(&NodeData<P, S>) -> S::Delta, C: FnOnce(usize) -> Result<(), TreeFileError>
+    {
+        let mut lock = self.guarded.lock().unwrap();
+        let mut node_pos = check_presence(&mut lock, leaf)?;
+
+        // All nodes on the path are updated in memory before anything is written, so a failing
+        // update leaves the tree untouched
+        let mut path: Vec<NodeData<P, S>> = Vec::new();
+        loop {
+            let mut node_data = get_node::<P, S, _>(&mut lock, node_pos)?;
+            let score = score_fn(&node_data);
+            let score = if flip_sign && path.len() % 2 == 1 {-score} else {score};
+            node_data.hits = add_and_subtract(node_data.hits, hits)?;
+            node_data.score = node_data.score.accumulate(score)?;
+
+            let parent = node_data.parent;
+            path.push(node_data);
+            match parent {
+                Some(parent) if include_top || parent != self.get_top() => node_pos = node_id_to_pos(&lock, parent),
+                _ => break,
+            }
+        }
+        before_write(path.len())?;
+
+        begin_transaction(&mut lock);
+        let res = path.iter().try_for_each(|node_data| update_node(&mut lock, node_data));
+        end_transaction(&mut lock, res)?;
+
+        Ok(path.len())
+    }
+
+    pub fn apply_batch(&mut self, batch: WriteBatch<K, S>) -> Result<Vec<NodeId>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        begin_transaction(&mut lock);
+        let res = apply_batch_ops::<K, P, S, _>(&mut lock, batch);
+        end_transaction(&mut lock, res)
+    }
+
+    pub fn verify(&self) -> Result<Vec<Inconsistency<K>>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        check_tree::<K, S, _>(&mut lock, false)
+    }
+
+    pub fn repair(&mut self) -> Result<Vec<Inconsistency<K>>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        check_tree::<K, S, _>(&mut lock, true)
+    }
+
+    pub fn set_write_ahead_log(&mut self, enabled: bool) -> Result<(), TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        if enabled && !St::PERSISTENT {
+            return Err(LogicError {msg: String::from("a write ahead log needs a persistent storage")});
+        }
+        if !enabled {
+            lock.wal = None;
+        } else if lock.wal.is_none() {
+            // Any log left by an earlier process was replayed when the tree was opened
+            let file = create_file(&wal_file_path(&self.path, self.file_prefix))?;
+            lock.wal = Some(file);
+        }
 
         Ok(())
     }
 
-    pub fn get_child_iter(&self, node: NodeId) -> Iter {
+    pub fn update_payload(&self, node: NodeId, payload: P) -> Result<(), TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let node_pos = check_presence(&mut lock, node)?;
+
+        let mut node_data = get_node::<P, S, _>(&mut lock, node_pos)?;
+        node_data.payload = payload;
+        begin_transaction(&mut lock);
+        let res = update_node(&mut lock, &node_data);
+        end_transaction(&mut lock, res)
+    }
+
+    pub fn get_child_iter(&self, node: NodeId) -> Iter<K> {
         let mut iter = Iter {
             key_vals: Vec::new(),
         };
 
         let mut lock = self.guarded.lock().unwrap();
-        if let Err(_) = check_presence(&mut lock, node) {
-            return iter;
-        }
+        let node_pos = match check_presence(&mut lock, node) {
+            Ok(node_pos) => node_pos,
+            Err(_) => return iter,
+        };
 
-        let node_pos = node_id_to_pos(node);
         let children_meta = get_node_child_meta(&mut lock, node_pos).unwrap();
         iter.key_vals = get_children_maps(&mut lock, None, &children_meta).unwrap()
             .child_maps.iter()
             .map(|cm | (cm.key, cm.node_id))
-            .collect::<Vec<(u16, NodeId)>>();
+            .collect::<Vec<(K, NodeId)>>();
 
         iter
     }
 
-    pub(crate) fn get_children(&self, node: NodeId) -> Result<Vec<(u16, NodeId)>, TreeFileError> {
+    pub fn compact(&self, dest_path: &str) -> Result<HashMap<NodeId, NodeId>, TreeFileError> {
+        // The copy of a storage that is not persisted would be dropped right away
+        if !St::PERSISTENT {
+            return Err(LogicError {msg: String::from("compacting needs a persistent storage, use reroot_in_place")});
+        }
+        check_dest_path::<St>(&self.path, dest_path)?;
+        let mut lock = self.guarded.lock().unwrap();
+
+        let top_pos = node_id_to_pos(&lock, self.get_top());
+        let dest_paths = tree_file_paths(dest_path, self.file_prefix);
+        let node_ids = copy_subtree::<K, P, S, _, St>(&mut lock, top_pos, &dest_paths)?.0;
+        clear_wal(dest_path, self.file_prefix)?;
+
+        Ok(node_ids)
+    }
+
+    pub fn reroot(&self, node: NodeId, dest_path: &str) -> Result<TreeMap<K, P, S, St>, TreeFileError> {
+        check_dest_path::<St>(&self.path, dest_path)?;
+        let storages = {
+            let mut lock = self.guarded.lock().unwrap();
+            let node_pos = check_presence(&mut lock, node)?;
+
+            let dest_paths = tree_file_paths(dest_path, self.file_prefix);
+            copy_subtree::<K, P, S, _, St>(&mut lock, node_pos, &dest_paths)?.1
+        };
+        if St::PERSISTENT {
+            clear_wal(dest_path, self.file_prefix)?;
+        }
+
+        TreeMap::from_storages(dest_path, 0, self.file_prefix, storages, true)
+    }
+
+    pub fn reroot_in_place(&mut self, node: NodeId) -> Result<HashMap<NodeId, NodeId>, TreeFileError> {
         let mut lock = self.guarded.lock().unwrap();
-        check_presence(&mut lock, node)?;
+        let node_pos = check_presence(&mut lock, node)?;
+
+        let (node_path, map_path, meta_path) = tree_file_paths(&self.path, self.file_prefix);
+        let tmp_paths = (format!("{}.tmp", node_path), format!("{}.tmp", map_path), format!("{}.tmp", meta_path));
+        let node_ids = if lock.wal.is_some() {
+            // With a write ahead log the copy overwrites the files in one transaction instead of being
+            // renamed into place file by file
+            let (node_ids, storages) = copy_subtree::<K, P, S, _, MemStorage>(&mut lock, node_pos, &tmp_paths)?;
+            begin_transaction(&mut lock);
+            let res = replace_files(&mut lock, storages);
+            end_transaction(&mut lock, res)?;
+            node_ids
+        } else {
+            let (node_ids, (node_file, map_file, meta_file)) = copy_subtree::<K, P, S, _, St>(&mut lock, node_pos, &tmp_paths)?;
+            lock.node_file = move_storage(node_file, &tmp_paths.0, &node_path)?;
+            lock.map_file = move_storage(map_file, &tmp_paths.1, &map_path)?;
+            lock.meta_file = move_storage(meta_file, &tmp_paths.2, &meta_path)?;
+            node_ids
+        };
+        lock.free_map_heads.clear();
+        count_nodes(&mut lock)?;
+        load_meta_data(&mut lock)?;
+
+        Ok(node_ids)
+    }
+
+    pub fn dfs(&self, start: NodeId) -> Traversal<'_, K, P, S> {
+        Traversal::new(start, true, |node, with_children| self.get_node_and_children(node, with_children))
+    }
+
+    pub fn bfs(&self, start: NodeId) -> Traversal<'_, K, P, S> {
+        Traversal::new(start, false, |node, with_children| self.get_node_and_children(node, with_children))
+    }
+
+    pub(crate) fn get_node_and_children(&self, node: NodeId, with_children: bool) -> Result<NodeAndChildren<K, P, S>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let node_pos = check_presence(&mut lock, node)?;
+        let node_data = get_node::<P, S, _>(&mut lock, node_pos)?;
+        if !with_children || node_data.n_children == 0 {
+            return Ok((node_data, Vec::new()));
+        }
 
-        let node_pos = node_id_to_pos(node);
+        let children_meta = ChildrenMeta {
+            first_child_pos: node_data.first_child_pos,
+            n_children: node_data.n_children,
+            max_children: node_data.max_children,
+            sorted: node_data.children_sorted,
+        };
+        let children = get_children_maps(&mut lock, None, &children_meta)?
+            .child_maps.iter()
+            .map(|cm | (cm.key, cm.node_id))
+            .collect::<Vec<(K, NodeId)>>();
+
+        Ok((node_data, children))
+    }
+
+    pub(crate) fn get_children(&self, node: NodeId) -> Result<Vec<(K, NodeId)>, TreeFileError> {
+        let mut lock = self.guarded.lock().unwrap();
+        let node_pos = check_presence(&mut lock, node)?;
         let children_meta = get_node_child_meta(&mut lock, node_pos).unwrap();
         Ok(get_children_maps(&mut lock, None, &children_meta)?
             .child_maps.iter()
             .map(|cm | (cm.key, cm.node_id))
-            .collect::<Vec<(u16, NodeId)>>())
+            .collect::<Vec<(K, NodeId)>>())
+    }
+}
+
+impl<K: Key, P: Payload, S: Score> TreeMap<K, P, S, MemStorage> {
+    pub fn load_from(path: &str, file_prefix: Option<u8>) -> Result<MemTreeMap<K, P, S>, TreeFileError> {
+        // Opening the files first validates their header and replays any write ahead log left behind
+        let tree = TreeMap::<K, P, S, FileStorage>::open(path, 0, MustExist, file_prefix)?;
+        let storages = {
+            let mut lock = tree.guarded.lock().unwrap();
+            let mut storages = (MemStorage::default(), MemStorage::default(), MemStorage::default());
+            for (tree_file, storage) in [(TreeFile::Node, &mut storages.0), (TreeFile::Map, &mut storages.1), (TreeFile::Meta, &mut storages.2)] {
+                copy_storage(tree_file_mut(&mut lock, tree_file), storage).map_err(|e| FileIOError {
+                    msg: format!("while reading from {} file: {}", tree_file.name(), e)
+                })?;
+            }
+            storages
+        };
+
+        TreeMap::from_storages(path, 0, file_prefix, storages, true)
+    }
+
+    pub fn save_to(&self, path: &str) -> Result<(), TreeFileError> {
+        // Each file is written next to its destination and renamed into place once complete
+        let mut lock = self.guarded.lock().unwrap();
+        let (node_path, map_path, meta_path) = tree_file_paths(path, self.file_prefix);
+
+        for (tree_file, dest_path) in [(TreeFile::Node, &node_path), (TreeFile::Map, &map_path), (TreeFile::Meta, &meta_path)] {
+            let tmp_path = format!("{}.tmp", dest_path);
+            let mut file = open_storage::<FileStorage>(&tmp_path, true)?;
+            copy_storage(tree_file_mut(&mut lock, tree_file), &mut file).and_then(|_| file.sync()).map_err(|e| FileIOError {
+                msg: format!("while writing to {} file: {}", tree_file.name(), e)
+            })?;
+            move_storage(file, &tmp_path, dest_path)?;
+        }
+
+        clear_wal(path, self.file_prefix)
     }
 }
 
-impl Drop for TreeMap {
+impl<K: Key, P: Payload, S: Score, St: Storage> Drop for TreeMap<K, P, S, St> {
     fn drop(&mut self) {
         let mut lock = self.guarded.lock().unwrap();
         let _ = lock.node_file.flush();
         let _ = lock.map_file.flush();
+        let _ = lock.meta_file.flush();
+    }
+}
+
+fn clear_wal(path: &str, file_prefix: Option<u8>) -> Result<(), TreeFileError> {
+    // A log left by an earlier tree at the destination does not belong to the copied files
+    let wal_path = wal_file_path(path, file_prefix);
+    if Path::new(&wal_path).is_file() {
+        create_file(&wal_path)?;
+    }
+
+    Ok(())
+}
+
+fn open_storage<St: Storage>(path: &str, create: bool) -> Result<St, TreeFileError> {
+    St::open(path, create).map_err(|e| FileIOError {
+        msg: format!("Error while opening file {}: {}", path, e)
+    })
+}
+
+fn move_storage<St: Storage>(storage: St, from: &str, to: &str) -> Result<St, TreeFileError> {
+    storage.move_to(from, to).map_err(|e| FileIOError {
+        msg: format!("Error while renaming file {} to {}: {}", from, to, e)
+    })
+}
+
+pub(crate) fn tree_header<K: Key, P: Payload, S: Score>(file_kind: FileKind, max_top_children: u32) -> Header {
+    Header {
+        file_kind,
+        node_length: (NODE_META_LENGTH + P::LENGTH) as u32,
+        map_length: map_length::<K>() as u32,
+        key_width: K::WIDTH as u16,
+        payload_length: P::LENGTH as u32,
+        score_kind: S::KIND,
+        max_top_children,
+    }
+}
+
+pub(crate) fn tree_file_paths(path: &str, file_prefix: Option<u8>) -> (String, String, String) {
+    let prefix = if let Some(p) = file_prefix {format!("{:03}.", p)} else {String::new()};
+
+    (
+        format!("{}/{}treemap.nodes.bin", path, prefix),
+        format!("{}/{}treemap.map.bin", path, prefix),
+        format!("{}/{}treemap.meta.bin", path, prefix),
+    )
+}
+
+fn wal_file_path(path: &str, file_prefix: Option<u8>) -> String {
+    let prefix = if let Some(p) = file_prefix {format!("{:03}.", p)} else {String::new()};
+    format!("{}/{}treemap.wal.bin", path, prefix)
+}
+
+fn check_dest_path<St: Storage>(path: &str, dest_path: &str) -> Result<(), TreeFileError> {
+    // Storages that are not persisted have no files to overwrite
+    if !St::PERSISTENT {
+        return Ok(());
+    }
+
+    let src = Path::new(path).canonicalize().map_err(|e| FileIOError {
+        msg: format!("Error while resolving path {}: {}", path, e)
+    })?;
+    let dest = Path::new(dest_path).canonicalize().map_err(|e| FileIOError {
+        msg: format!("Error while resolving path {}: {}", dest_path, e)
+    })?;
+
+    if src == dest {
+        Err(LogicError {msg: String::from("destination path must differ from the path of the tree")})
+    } else {
+        Ok(())
+    }
+}
+
+fn copy_subtree<K: Key, P: Payload, S: Score, St: Storage, D: Storage>(lock: &mut MutexGuard<FileData<St>>, start_pos: u64, dest_paths: &(String, String, String)) -> Result<CopiedTree<D>, TreeFileError> {
+    // Nodes are written in breadth first order, so a node's children get consecutive ids and their
+    // ids are known by the time the parent's child map block is written
+    let (node_path, map_path, meta_path) = dest_paths;
+    let mut node_file = open_storage::<D>(node_path, true)?;
+    let mut map_file = open_storage::<D>(map_path, true)?;
+    let mut meta_file = open_storage::<D>(meta_path, true)?;
+    let mut node_writer = StorageWriter::new(&mut node_file);
+    let mut map_writer = StorageWriter::new(&mut map_file);
+
+    let mut node_ids: HashMap<NodeId, NodeId> = HashMap::new();
+    let mut queue: VecDeque<(u64, u64)> = VecDeque::from([(start_pos, NO_PARENT_POS)]);
+    let mut next_node_id: NodeId = 1;
+    let mut map_pos = HEADER_LENGTH as u64;
+
+    let header = lock.header;
+    node_writer.write_all(&header.to_buf()).map_err(|e| FileIOError {
+        msg: format!("while writing to node file: {}", e)
+    })?;
+    map_writer.write_all(&Header { file_kind: FileKind::Map, ..header }.to_buf()).map_err(|e| FileIOError {
+        msg: format!("while writing to map file: {}", e)
+    })?;
+
+    while let Some((old_pos, parent_pos)) = queue.pop_front() {
+        let mut node_data = get_node::<P, S, _>(lock, old_pos)?;
+        let new_pos = node_id_to_pos(lock, node_ids.len());
+        node_ids.insert(node_data.node_id, node_ids.len());
+
+        if node_data.n_children > 0 {
+            let children_meta = ChildrenMeta {
+                first_child_pos: node_data.first_child_pos,
+                n_children: node_data.n_children,
+                max_children: node_data.max_children,
+                sorted: node_data.children_sorted,
+            };
+            let child_maps = get_children_maps(lock, None, &children_meta)?.child_maps.into_iter()
+                .map(|cm| {
+                    queue.push_back((cm.node_pos, new_pos));
+                    let node_pos = node_id_to_pos(lock, next_node_id);
+                    next_node_id += 1;
+                    ChildMap { node_id: pos_to_node_id(lock, node_pos), node_pos, key: cm.key }
+                })
+                .collect::<Vec<ChildMap<K>>>();
+
+            map_writer.write_all(&children_to_buf(child_maps, node_data.max_children)).map_err(|e| FileIOError {
+                msg: format!("while writing to map file: {}", e)
+            })?;
+            node_data.first_child_pos = map_pos;
+            node_data.children_sorted = true;
+            map_pos += (map_length::<K>() * node_data.max_children as usize) as u64;
+        } else {
+            node_data.first_child_pos = 0;
+        }
+
+        node_writer.write_all(&node_to_buf(parent_pos, &node_data)).map_err(|e| FileIOError {
+            msg: format!("while writing to node file: {}", e)
+        })?;
+    }
+
+    node_writer.flush().map_err(|e| FileIOError {
+        msg: format!("while writing to node file: {}", e)
+    })?;
+    map_writer.flush().map_err(|e| FileIOError {
+        msg: format!("while writing to map file: {}", e)
+    })?;
+    let mut meta_buf = Header { file_kind: FileKind::Meta, ..header }.to_buf().to_vec();
+    meta_buf.extend(meta_to_buf(NO_FREE_POS, 0, &HashMap::new()));
+    meta_file.write_at(0, &meta_buf).map_err(|e| FileIOError {
+        msg: format!("while writing to meta file: {}", e)
+    })?;
+
+    Ok((node_ids, (node_file, map_file, meta_file)))
+}
+
+fn replace_files<St: Storage>(lock: &mut MutexGuard<FileData<St>>, storages: (MemStorage, MemStorage, MemStorage)) -> Result<(), TreeFileError> {
+    let (node_file, map_file, meta_file) = storages;
+    for (tree_file, storage) in [(TreeFile::Node, node_file), (TreeFile::Map, map_file), (TreeFile::Meta, meta_file)] {
+        let buf = storage.into_data();
+        write_at(lock, tree_file, 0, &buf)?;
+        truncate_file(lock, tree_file, buf.len() as u64)?;
+    }
+
+    Ok(())
+}
+
+fn write_at<St: Storage>(lock: &mut MutexGuard<FileData<St>>, tree_file: TreeFile, pos: u64, buf: &[u8]) -> Result<(), TreeFileError> {
+    // Inside a transaction writes are held back until the whole operation has succeeded
+    if let Some(pending) = lock.pending.as_mut() {
+        pending.write(tree_file, pos, buf);
+        return Ok(());
+    }
+
+    tree_file_mut(lock, tree_file).write_at(pos, buf).map_err(|e| FileIOError {
+        msg: format!("while writing to {} file: {}", tree_file.name(), e)
+    })
+}
+
+fn read_at<St: Storage>(lock: &mut MutexGuard<FileData<St>>, tree_file: TreeFile, pos: u64, buf: &mut [u8]) -> std::io::Result<()> {
+    // Inside a transaction reads see the writes held back so far
+    let (pending_end, pending_len) = match lock.pending.as_ref() {
+        Some(pending) => (pending.end(tree_file), pending.len(tree_file)),
+        None => return tree_file_mut(lock, tree_file).read_at(pos, buf),
+    };
+
+    // Only reads reaching past the stored end need the length of the storage
+    let end = pos + buf.len() as u64;
+    if pending_len.is_some_and(|len| end > len) {
+        return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "read past the end of the truncated file"));
+    }
+    let storage = tree_file_mut(lock, tree_file);
+    match storage.read_at(pos, buf) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof && end <= pending_end => {
+            let stored_end = storage.len()?;
+            if pos < stored_end {
+                storage.read_at(pos, &mut buf[..(stored_end - pos) as usize])?;
+            }
+        },
+        res => res?,
+    }
+
+    if let Some(pending) = lock.pending.as_ref() {
+        pending.read(tree_file, pos, buf);
+    }
+    Ok(())
+}
+
+fn file_end<St: Storage>(lock: &mut MutexGuard<FileData<St>>, tree_file: TreeFile) -> u64 {
+    let (pending_end, pending_len) = lock.pending.as_ref().map_or((0, None), |pending| (pending.end(tree_file), pending.len(tree_file)));
+    pending_len.unwrap_or_else(|| tree_file_mut(lock, tree_file).len().unwrap().max(pending_end))
+}
+
+fn tree_file_mut<St: Storage>(lock: &mut FileData<St>, tree_file: TreeFile) -> &mut St {
+    match tree_file {
+        TreeFile::Node => &mut lock.node_file,
+        TreeFile::Map => &mut lock.map_file,
+        TreeFile::Meta => &mut lock.meta_file,
+    }
+}
+
+fn begin_transaction<St: Storage>(lock: &mut MutexGuard<FileData<St>>) {
+    lock.pending = Some(PendingWrites::default());
+}
+
+fn end_transaction<T, St: Storage>(lock: &mut MutexGuard<FileData<St>>, res: Result<T, TreeFileError>) -> Result<T, TreeFileError> {
+    let pending = match lock.pending.take() {
+        Some(pending) => pending,
+        None => return res,
+    };
+
+    // After a failure the in memory state no longer matches the tree files and is reloaded from them
+    match res.and_then(|value| commit_records(lock, pending.into_records()).map(|_| value)) {
+        Ok(value) => Ok(value),
+        Err(e) => {
+            lock.free_map_heads.clear();
+            count_nodes(lock)?;
+            load_meta_data(lock)?;
+            Err(e)
+        },
+    }
+}
+
+fn commit_records<St: Storage>(lock: &mut MutexGuard<FileData<St>>, records: Vec<WalRecord>) -> Result<(), TreeFileError> {
+    // Without a write ahead log the records are written as they are
+    if lock.wal.is_none() {
+        return write_records(lock, &records);
+    }
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let buf = records_to_buf(&records);
+    let wal = lock.wal.as_mut().unwrap();
+    wal.seek(SeekFrom::Start(0)).and_then(|_| wal.write_all(&buf)).and_then(|_| wal.sync_data()).map_err(|e| FileIOError {
+        msg: format!("while writing to wal file: {}", e)
+    })?;
+
+    apply_records(lock, &records)?;
+
+    let wal = lock.wal.as_mut().unwrap();
+    wal.set_len(0).and_then(|_| wal.sync_data()).map_err(|e| FileIOError {
+        msg: format!("while truncating wal file: {}", e)
+    })
+}
+
+fn write_records<St: Storage>(lock: &mut MutexGuard<FileData<St>>, records: &[WalRecord]) -> Result<(), TreeFileError> {
+    for record in records {
+        if record.data.is_empty() {
+            truncate_file(lock, record.tree_file, record.pos)?;
+        } else {
+            write_at(lock, record.tree_file, record.pos, &record.data)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn apply_records<St: Storage>(lock: &mut MutexGuard<FileData<St>>, records: &[WalRecord]) -> Result<(), TreeFileError> {
+    write_records(lock, records)?;
+
+    for tree_file in [TreeFile::Node, TreeFile::Map, TreeFile::Meta] {
+        tree_file_mut(lock, tree_file).sync().map_err(|e| FileIOError {
+            msg: format!("while syncing {} file: {}", tree_file.name(), e)
+        })?;
+    }
+
+    Ok(())
+}
+
+fn replay_wal<St: Storage>(lock: &mut MutexGuard<FileData<St>>, wal_path: &str) -> Result<(), TreeFileError> {
+    if !Path::new(wal_path).is_file() {
+        return Ok(());
+    }
+
+    let mut file = open_file(wal_path)?;
+    let mut buf: Vec<u8> = Vec::new();
+    file.read_to_end(&mut buf).map_err(|e| FileIOError {
+        msg: format!("while reading from wal file: {}", e)
+    })?;
+
+    if let Some(records) = records_from_buf(&buf) {
+        apply_records(lock, &records)?;
+    }
+    file.set_len(0).map_err(|e| FileIOError {
+        msg: format!("while truncating wal file: {}", e)
+    })
+}
+
+fn check_tree<K: Key, S: Score, St: Storage>(lock: &mut MutexGuard<FileData<St>>, repair: bool) -> Result<Vec<Inconsistency<K>>, TreeFileError> {
+    // Repair truncates partial tail records and drops child map entries that do not point back to
+    // a node whose parent is the owner, other inconsistencies are only reported
+    let mut issues: Vec<Inconsistency<K>> = Vec::new();
+
+    let header_length = HEADER_LENGTH as u64;
+    let node_length = lock.node_length as u64;
+    let node_file_length = file_end(lock, TreeFile::Node);
+    if (node_file_length - header_length) % node_length != 0 {
+        issues.push(Inconsistency::PartialNodeRecord {file_length: node_file_length});
+        if repair {
+            truncate_file(lock, TreeFile::Node, node_file_length - (node_file_length - header_length) % node_length)?;
+            count_nodes(lock)?;
+            rebuild_free_nodes(lock)?;
+            save_meta_data(lock)?;
+        }
+    }
+
+    let map_record_length = map_length::<K>() as u64;
+    let map_file_length = file_end(lock, TreeFile::Map);
+    if (map_file_length - header_length) % map_record_length != 0 {
+        issues.push(Inconsistency::PartialMapRecord {file_length: map_file_length});
+        if repair {
+            truncate_file(lock, TreeFile::Map, map_file_length - (map_file_length - header_length) % map_record_length)?;
+            lock.free_map_heads.clear();
+            save_meta_data(lock)?;
+        }
+    }
+    let map_file_length = file_end(lock, TreeFile::Map);
+
+    // First pass collects which nodes are free and every node's parent, the second checks each node
+    // against them
+    let n_nodes = lock.n_nodes;
+    let mut free = vec![false;n_nodes];
+    let mut parents: Vec<Option<NodeId>> = vec![None;n_nodes];
+    for chunk_start in (0..n_nodes).step_by(VERIFY_CHUNK_LENGTH) {
+        let node_positions = (chunk_start..n_nodes.min(chunk_start + VERIFY_CHUNK_LENGTH))
+            .map(|node| node_id_to_pos(lock, node))
+            .collect::<Vec<u64>>();
+        for (i, node_data) in get_nodes_at::<(), S, _>(lock, &node_positions).into_iter().enumerate() {
+            match node_data {
+                Ok(node_data) => parents[chunk_start + i] = node_data.parent,
+                Err(NonExistingNode) => free[chunk_start + i] = true,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    let mut referenced = vec![false;n_nodes];
+    for node in 0..n_nodes {
+        if free[node] {
+            continue;
+        }
+
+        let valid_parent = match parents[node] {
+            Some(parent) => node != 0 && parent < n_nodes && !free[parent],
+            None => node == 0,
+        };
+        if !valid_parent {
+            issues.push(Inconsistency::BadParent {node});
+        }
+
+        let node_pos = node_id_to_pos(lock, node);
+        let mut children_meta = get_node_child_meta(lock, node_pos)?;
+        if children_meta.n_children > children_meta.max_children {
+            issues.push(Inconsistency::TooManyChildren {node, n_children: children_meta.n_children, max_children: children_meta.max_children});
+        }
+        if children_meta.n_children == 0 {
+            continue;
+        }
+
+        if children_meta.first_child_pos < header_length || children_meta.first_child_pos + children_meta.n_children as u64 * map_record_length > map_file_length {
+            issues.push(Inconsistency::ChildMapOutOfBounds {node});
+            if repair {
+                children_meta.n_children = 0;
+                children_meta.first_child_pos = 0;
+                update_node_child_meta(lock, node_pos, &children_meta)?;
+            }
+            continue;
+        }
+
+        let mut child_maps = get_children_maps::<K, _>(lock, None, &children_meta)?.child_maps;
+        let n_children = child_maps.len();
+        child_maps.retain(|cm| {
+            let valid = cm.node_pos >= header_length && (cm.node_pos - header_length) % node_length == 0 && cm.node_id < n_nodes && !free[cm.node_id] && parents[cm.node_id] == Some(node);
+            if valid {
+                referenced[cm.node_id] = true;
+            } else {
+                issues.push(Inconsistency::DanglingChildMap {node, key: cm.key});
+            }
+            valid
+        });
+
+        if repair && child_maps.len() != n_children {
+            children_meta.n_children = child_maps.len() as u32;
+            if child_maps.is_empty() {
+                free_child_map(lock, children_meta.first_child_pos, children_meta.max_children)?;
+                save_meta_data(lock)?;
+                children_meta.first_child_pos = 0;
+            } else {
+                update_children_maps(lock, child_maps, &mut children_meta)?;
+            }
+            update_node_child_meta(lock, node_pos, &children_meta)?;
+        }
+    }
+
+    for node in 1..n_nodes {
+        if !free[node] && !referenced[node] {
+            issues.push(Inconsistency::Orphan {node});
+        }
+    }
+
+    Ok(issues)
+}
+
+fn truncate_file<St: Storage>(lock: &mut MutexGuard<FileData<St>>, tree_file: TreeFile, length: u64) -> Result<(), TreeFileError> {
+    if let Some(pending) = lock.pending.as_mut() {
+        pending.set_len(tree_file, length);
+        return Ok(());
+    }
+
+    tree_file_mut(lock, tree_file).set_len(length).map_err(|e| FileIOError {
+        msg: format!("while truncating {} file: {}", tree_file.name(), e)
+    })
+}
+
+fn check_headers<St: Storage>(lock: &mut MutexGuard<FileData<St>>) -> Result<(), TreeFileError> {
+    // New files get the header of the tree opening them, existing files must carry a matching one
+    let node_header = lock.header;
+    let map_header = Header { file_kind: FileKind::Map, ..node_header };
+    let meta_header = Header { file_kind: FileKind::Meta, ..node_header };
+
+    for (tree_file, expected) in [(TreeFile::Node, node_header), (TreeFile::Map, map_header), (TreeFile::Meta, meta_header)] {
+        if file_end(lock, tree_file) == 0 {
+            write_at(lock, tree_file, 0, &expected.to_buf())?;
+            continue;
+        }
+
+        let file_length = file_end(lock, tree_file);
+        let mut buf = vec![0u8;HEADER_LENGTH.min(file_length as usize)];
+        read_at(lock, tree_file, 0, &mut buf).map_err(|e| FileIOError {
+            msg: format!("while reading from {} file: {}", tree_file.name(), e)
+        })?;
+        let header = Header::from_buf(&buf)?;
+        header.check(&expected)?;
+        if tree_file == TreeFile::Node {
+            lock.header.max_top_children = header.max_top_children;
+        }
+    }
+
+    Ok(())
+}
+
+fn count_nodes<St: Storage>(lock: &mut MutexGuard<FileData<St>>) -> Result<(), TreeFileError> {
+    let node_file_length = file_end(lock, TreeFile::Node);
+    lock.n_nodes = (node_file_length.saturating_sub(HEADER_LENGTH as u64) / lock.node_length as u64) as usize;
+
+    Ok(())
+}
+
+fn load_meta_data<St: Storage>(lock: &mut MutexGuard<FileData<St>>) -> Result<(), TreeFileError> {
+    let meta_file_length = file_end(lock, TreeFile::Meta);
+    let mut buf = vec![0u8;meta_file_length.saturating_sub(HEADER_LENGTH as u64) as usize];
+    read_at(lock, TreeFile::Meta, HEADER_LENGTH as u64, &mut buf).map_err(|e| FileIOError {
+        msg: format!("while reading from meta file: {}", e)
+    })?;
+
+    if buf.len() < META_MIN_LENGTH {
+        rebuild_free_nodes(lock)?;
+        return save_meta_data(lock);
+    }
+
+    lock.free_node_head = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+    lock.n_free = u64::from_le_bytes(buf[8..16].try_into().unwrap()) as usize;
+    let n_classes = u32::from_le_bytes(buf[16..20].try_into().unwrap()) as usize;
+
+    if buf.len() < META_MIN_LENGTH + n_classes * META_CLASS_LENGTH {
+        return Err(LogicError {msg: String::from("to few free map classes in meta file")});
+    }
+
+    for class_no in 0..n_classes {
+        let offset = META_MIN_LENGTH + class_no * META_CLASS_LENGTH;
+        let max_children = u32::from_le_bytes(buf[offset..4+offset].try_into().unwrap());
+        let head = u64::from_le_bytes(buf[4+offset..12+offset].try_into().unwrap());
+        lock.free_map_heads.insert(max_children, head);
+    }
+
+    Ok(())
+}
+
+fn save_meta_data<St: Storage>(lock: &mut MutexGuard<FileData<St>>) -> Result<(), TreeFileError> {
+    let buf = meta_to_buf(lock.free_node_head, lock.n_free, &lock.free_map_heads);
+
+    write_at(lock, TreeFile::Meta, HEADER_LENGTH as u64, &buf)
+}
+
+fn rebuild_free_nodes<St: Storage>(lock: &mut MutexGuard<FileData<St>>) -> Result<(), TreeFileError> {
+    lock.n_free = 0;
+    lock.free_node_head = NO_FREE_POS;
+
+    let mut buf = [0u8;8];
+    for node_id in 0..lock.n_nodes {
+        let node_pos = node_id_to_pos(lock, node_id);
+        read_at(lock, TreeFile::Node, node_pos, &mut buf).map_err(|e| FileIOError {
+            msg: format!("while reading from node file: {}", e)
+        })?;
+        if u64::from_le_bytes(buf) == FREE_NODE_POS {
+            free_node(lock, node_pos)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn unlink_child<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, parent_pos: u64, child_pos: u64) -> Result<(), TreeFileError> {
+    let mut children_meta = get_node_child_meta(lock, parent_pos)?;
+    let mut res = get_children_maps::<K, _>(lock, None, &children_meta)?;
+    res.child_maps.retain(|cm| cm.node_pos != child_pos);
+
+    let new_children_len = res.child_maps.len() as u32;
+    if new_children_len == children_meta.n_children {
+        return Err(LogicError {
+            msg: String::from("child node not found among the parents child maps")
+        });
     }
+
+    if new_children_len == 0 {
+        free_child_map(lock, children_meta.first_child_pos, children_meta.max_children)?;
+        children_meta.first_child_pos = 0;
+    } else {
+        update_children_maps(lock, res.child_maps, &mut children_meta)?;
+    }
+    children_meta.n_children = new_children_len;
+    update_node_child_meta(lock, parent_pos, &children_meta)?;
+
+    Ok(())
+}
+
+fn free_subtree<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, node_pos: u64) -> Result<usize, TreeFileError> {
+    let mut n_freed: usize = 0;
+    let mut stack: Vec<u64> = Vec::from([node_pos]);
+
+    while let Some(pos) = stack.pop() {
+        let children_meta = get_node_child_meta(lock, pos)?;
+        if children_meta.n_children > 0 {
+            get_children_maps::<K, _>(lock, None, &children_meta)?
+                .child_maps.iter()
+                .for_each(|cm| stack.push(cm.node_pos));
+            free_child_map(lock, children_meta.first_child_pos, children_meta.max_children)?;
+        }
+
+        free_node(lock, pos)?;
+        n_freed += 1;
+    }
+    save_meta_data(lock)?;
+
+    Ok(n_freed)
+}
+
+fn free_node<St: Storage>(lock: &mut MutexGuard<FileData<St>>, node_pos: u64) -> Result<(), TreeFileError> {
+    // A free node record links to the next free node through its first child pos, its payload is left as is
+    let node_data = NodeData {
+        node_id: pos_to_node_id(lock, node_pos),
+        node_pos,
+        parent: None,
+        hits: 0,
+        score: 0u64,
+        first_child_pos: lock.free_node_head,
+        n_children: 0,
+        max_children: 0,
+        children_sorted: false,
+        payload: (),
+    };
+    write_at(lock, TreeFile::Node, node_pos, &node_to_buf(FREE_NODE_POS, &node_data))?;
+    lock.free_node_head = node_pos;
+    lock.n_free += 1;
+
+    Ok(())
 }
 
-fn count_nodes(lock: &mut MutexGuard<FileData>) -> Result<(), TreeFileError> {
-    lock.node_file.sync_all().unwrap();
-    let metadata = lock.node_file.metadata().unwrap();
-    lock.n_nodes = (metadata.len() / NODE_LENGTH as u64) as usize;
+fn free_child_map<St: Storage>(lock: &mut MutexGuard<FileData<St>>, children_pos: u64, max_children: u32) -> Result<(), TreeFileError> {
+    // A free child map block links to the next free block of the same size through its first 8 bytes
+    let next_pos = *lock.free_map_heads.get(&max_children).unwrap_or(&NO_FREE_POS);
+    write_at(lock, TreeFile::Map, children_pos, &next_pos.to_le_bytes())?;
+    lock.free_map_heads.insert(max_children, children_pos);
 
     Ok(())
 }
 
-fn new_children_child_mappings(lock: &mut MutexGuard<FileData>, parent_pos: u64, key: u16, child_pos: u64, children_meta: &mut ChildrenMeta) -> Result<(), TreeFileError> {
+fn next_free_pos<St: Storage>(lock: &mut MutexGuard<FileData<St>>, tree_file: TreeFile, pos: u64, offset: u64) -> Result<u64, TreeFileError> {
+    let mut buf = [0u8;8];
+    read_at(lock, tree_file, pos + offset, &mut buf).map_err(|e| FileIOError {
+        msg: format!("while reading free list link: {}", e)
+    })?;
+
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn new_children_child_mappings<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, parent_pos: u64, key: K, child_pos: u64, children_meta: &mut ChildrenMeta) -> Result<(), TreeFileError> {
     if children_meta.max_children == 0 {
         return Err(LogicError {
             msg: String::from("trying to add more children than allowed for parent")
@@ -212,25 +1263,26 @@ fn new_children_child_mappings(lock: &mut MutexGuard<FileData>, parent_pos: u64,
     }
 
     let new_child_map = ChildMap{
-        node_id: pos_to_node_id(child_pos),
+        node_id: pos_to_node_id(lock, child_pos),
         node_pos: child_pos,
         key,
     };
     children_meta.n_children = 1;
-    children_meta.first_child_pos = add_child_map(lock, new_child_map, children_meta.max_children)?;
-    update_node_child_meta(lock, parent_pos, &children_meta)?;
+    children_meta.first_child_pos = add_children_maps(lock, Vec::from([new_child_map]), children_meta.max_children)?;
+    children_meta.sorted = true;
+    update_node_child_meta(lock, parent_pos, children_meta)?;
 
     Ok(())
 }
 
-fn update_children_child_mappings(lock: &mut MutexGuard<FileData>, parent_pos: u64, key: u16, child_pos: u64, children_meta: &mut ChildrenMeta) -> Result<(), TreeFileError> {
+fn update_children_child_mappings<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, parent_pos: u64, key: K, child_pos: u64, children_meta: &mut ChildrenMeta) -> Result<(), TreeFileError> {
     let mut res = get_children_maps(lock, Some(key), children_meta)?;
-    if let Some(_) = res.key_hit {
+    if res.key_hit.is_some() {
         return Err(LogicError {
             msg: String::from("key already present, would turn existing child node to a ghost node")
         });
     } else {
-        res.child_maps.push(ChildMap{ node_id: pos_to_node_id(child_pos),  node_pos: child_pos, key })
+        res.child_maps.push(ChildMap{ node_id: pos_to_node_id(lock, child_pos),  node_pos: child_pos, key })
     }
 
     let new_children_len = res.child_maps.len() as u32;
@@ -244,40 +1296,199 @@ fn update_children_child_mappings(lock: &mut MutexGuard<FileData>, parent_pos: u
 
     if new_children_len != children_meta.n_children {
         children_meta.n_children = new_children_len;
-        update_node_child_meta(lock, parent_pos, &children_meta)?;
+        update_node_child_meta(lock, parent_pos, children_meta)?;
     }
 
     Ok(())
 }
 
-fn get_node(lock: &mut MutexGuard<FileData>, node_pos: u64) -> Result<NodeData, TreeFileError> {
-    let mut buf = [0u8;NODE_LENGTH];
-    let _ = lock.node_file.seek(SeekFrom::Start(node_pos)).unwrap();
-    lock.node_file.read_exact(&mut buf).map_err(|e| FileIOError {
-        msg: String::from(format!("while reading from node file: {}", e))
+fn relocate_children_maps<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, children_meta: &mut ChildrenMeta, max_children: u32) -> Result<(), TreeFileError> {
+    let res = get_children_maps::<K, _>(lock, None, children_meta)?;
+    let children_pos = add_children_maps(lock, res.child_maps, max_children)?;
+    free_child_map(lock, children_meta.first_child_pos, children_meta.max_children)?;
+    save_meta_data(lock)?;
+    children_meta.first_child_pos = children_pos;
+    children_meta.sorted = true;
+
+    Ok(())
+}
+
+fn add_child_node<K: Key, P: Payload, S: Score, St: Storage>(lock: &mut MutexGuard<FileData<St>>, parent_pos: u64, key: K, hits: u64, score: S, max_children: u32, payload: P) -> Result<u64, TreeFileError> {
+    let child_pos = expected_node_pos(lock);
+
+    let mut children_meta = get_node_child_meta(lock, parent_pos)?;
+
+    if children_meta.n_children == 0 {
+        new_children_child_mappings(lock, parent_pos, key, child_pos, &mut children_meta)?;
+    } else {
+        update_children_child_mappings(lock, parent_pos, key, child_pos, &mut children_meta)?;
+    }
+
+    add_node(lock, parent_pos, hits, score, max_children, payload)
+}
+
+fn apply_batch_ops<K: Key, P: Payload, S: Score, St: Storage>(lock: &mut MutexGuard<FileData<St>>, batch: WriteBatch<K, S>) -> Result<Vec<NodeId>, TreeFileError> {
+    // Children are added in batch order, node updates are merged per node and written afterwards, so
+    // each updated node is read and written once. The caller's transaction holds back all writes until
+    // the whole batch has succeeded and then writes them in runs of adjacent records
+    let mut added: Vec<NodeId> = Vec::new();
+    let mut updates: Vec<(u64, i64, Vec<S::Delta>)> = Vec::new();
+    let mut update_index: HashMap<NodeId, usize> = HashMap::new();
+    for op in batch.ops {
+        match op {
+            BatchOp::AddChild {node, key, hits, score, max_children} => {
+                let parent_pos = check_presence(lock, node)?;
+                let child_pos = add_child_node(lock, parent_pos, key, hits, score, max_children, P::default())?;
+                added.push(pos_to_node_id(lock, child_pos));
+            },
+            BatchOp::UpdateNodeAdd {node, hits, score} => {
+                match update_index.get(&node) {
+                    Some(&i) => {
+                        updates[i].1 += hits;
+                        updates[i].2.push(score);
+                    },
+                    None => {
+                        let node_pos = check_presence(lock, node)?;
+                        update_index.insert(node, updates.len());
+                        updates.push((node_pos, hits, Vec::from([score])));
+                    },
+                }
+            },
+        }
+    }
+
+    let node_positions = updates.iter().map(|u| u.0).collect::<Vec<u64>>();
+    let mut updated_nodes: Vec<NodeData<P, S>> = Vec::new();
+    for (node_data, (_, hits, scores)) in get_nodes_at::<P, S, _>(lock, &node_positions).into_iter().zip(updates) {
+        let mut node_data = node_data?;
+        node_data.hits = add_and_subtract(node_data.hits, hits)?;
+        for score in scores {
+            node_data.score = node_data.score.accumulate(score)?;
+        }
+        updated_nodes.push(node_data);
+    }
+    update_nodes(lock, updated_nodes)?;
+
+    Ok(added)
+}
+
+fn best_child<K: Key, P: Payload, S: Score, St: Storage>(lock: &mut MutexGuard<FileData<St>>, node_pos: u64, policy: SelectionPolicy, parent_hits: u64, n_siblings: u32) -> Result<Option<ValuedSelection<K, P, S>>, TreeFileError> {
+    let children_meta = get_node_child_meta(lock, node_pos)?;
+    if children_meta.n_children == 0 {
+        return Ok(None);
+    }
+
+    let uniform_prior = 1.0 / n_siblings.max(1) as f64;
+    let mut best: Option<ValuedSelection<K, P, S>> = None;
+    let child_maps = get_children_maps::<K, _>(lock, None, &children_meta)?.child_maps;
+    let node_positions = child_maps.iter().map(|cm| cm.node_pos).collect::<Vec<u64>>();
+    for (cm, node_data) in child_maps.iter().zip(get_nodes_at::<P, S, _>(lock, &node_positions)) {
+        let node_data = node_data?;
+        let prior = node_data.payload.prior().map_or(uniform_prior, |p| p as f64);
+        let value = policy.value(parent_hits, node_data.hits, node_data.score.to_f64(), prior);
+
+        if best.as_ref().map_or(true, |&(best_value, best_key, _)| is_better_child(value, cm.key, best_value, best_key)) {
+            best = Some((value, cm.key, node_data));
+        }
+    }
+
+    Ok(best)
+}
+
+fn follow_path<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, start_pos: u64, keys: &[K]) -> Result<(u64, usize), TreeFileError> {
+    // Returns the deepest node reached along the keys and the number of keys followed to get there
+    let mut node_pos = start_pos;
+    for (depth, &key) in keys.iter().enumerate() {
+        let children_meta = get_node_child_meta(lock, node_pos)?;
+        if children_meta.n_children == 0 {
+            return Ok((node_pos, depth));
+        }
+
+        match find_child_map(lock, key, &children_meta)? {
+            Some(c) => node_pos = c.node_pos,
+            None => return Ok((node_pos, depth)),
+        }
+    }
+
+    Ok((node_pos, keys.len()))
+}
+
+fn get_node<P: Payload, S: Score, St: Storage>(lock: &mut MutexGuard<FileData<St>>, node_pos: u64) -> Result<NodeData<P, S>, TreeFileError> {
+    let mut buf = vec![0u8;lock.node_length];
+    read_at(lock, TreeFile::Node, node_pos, &mut buf).map_err(|e| FileIOError {
+        msg: format!("while reading from node file: {}", e)
     })?;
 
+    Ok(node_from_buf(lock, node_pos, &buf))
+}
+
+fn get_nodes_at<P: Payload, S: Score, St: Storage>(lock: &mut MutexGuard<FileData<St>>, node_positions: &[u64]) -> Vec<Result<NodeData<P, S>, TreeFileError>> {
+    // Positions are visited in file order and each run of adjacent records is read in one go
+    let mut order = (0..node_positions.len()).collect::<Vec<usize>>();
+    order.sort_unstable_by_key(|&i| node_positions[i]);
+
+    let node_length = lock.node_length as u64;
+    let mut results = node_positions.iter().map(|_| Err(NonExistingNode)).collect::<Vec<Result<NodeData<P, S>, TreeFileError>>>();
+    let mut run_start: usize = 0;
+    while run_start < order.len() {
+        let mut run_end = run_start + 1;
+        while run_end < order.len() && node_positions[order[run_end]] <= node_positions[order[run_end - 1]] + node_length {
+            run_end += 1;
+        }
+
+        let first_pos = node_positions[order[run_start]];
+        let last_pos = node_positions[order[run_end - 1]];
+        let mut buf = vec![0u8;(last_pos - first_pos + node_length) as usize];
+        let read = read_at(lock, TreeFile::Node, first_pos, &mut buf);
+
+        for &i in &order[run_start..run_end] {
+            let offset = (node_positions[i] - first_pos) as usize;
+            let node_buf = &buf[offset..offset + lock.node_length];
+            results[i] = match read {
+                Ok(()) if u64::from_le_bytes(node_buf[0..8].try_into().unwrap()) == FREE_NODE_POS => Err(NonExistingNode),
+                Ok(()) => Ok(node_from_buf(lock, node_positions[i], node_buf)),
+                Err(ref e) => Err(FileIOError {msg: format!("while reading from node file: {}", e)}),
+            };
+        }
+        run_start = run_end;
+    }
+
+    results
+}
+
+fn node_from_buf<P: Payload, S: Score, St: Storage>(lock: &FileData<St>, node_pos: u64, buf: &[u8]) -> NodeData<P, S> {
     let parent_pos = u64::from_le_bytes(buf[0..8].try_into().unwrap());
     let hits = u64::from_le_bytes(buf[8..16].try_into().unwrap());
-    let score = u64::from_le_bytes(buf[16..24].try_into().unwrap());
+    let score = S::from_le_buf(&buf[16..24]);
     let first_child_pos = u64::from_le_bytes(buf[24..32].try_into().unwrap());
     let n_children = u32::from_le_bytes(buf[32..36].try_into().unwrap());
     let max_children = u32::from_le_bytes(buf[36..40].try_into().unwrap());
 
-    Ok(NodeData{
-        node_id: pos_to_node_id(node_pos),
+    NodeData{
+        node_id: pos_to_node_id(lock, node_pos),
         node_pos,
-        parent: if parent_pos == u64::MAX {None} else {Some(pos_to_node_id(parent_pos))},
+        parent: if parent_pos == NO_PARENT_POS {None} else {Some(pos_to_node_id(lock, parent_pos))},
         hits,
         score,
         first_child_pos,
-        n_children,
+        n_children: n_children & !CHILDREN_SORTED_FLAG,
         max_children,
-    })
+        children_sorted: n_children & CHILDREN_SORTED_FLAG != 0,
+        payload: P::decode(&buf[NODE_META_LENGTH..]),
+    }
 }
 
-fn add_node(lock: &mut MutexGuard<FileData>, parent_pos: u64, hits: u64, score: u64, max_children: u32) -> Result<u64, TreeFileError> {
-    let node_pos = lock.node_file.seek(SeekFrom::End(0)).unwrap();
+fn add_node<P: Payload, S: Score, St: Storage>(lock: &mut MutexGuard<FileData<St>>, parent_pos: u64, hits: u64, score: S, max_children: u32, payload: P) -> Result<u64, TreeFileError> {
+    let node_pos = if lock.free_node_head != NO_FREE_POS {
+        let node_pos = lock.free_node_head;
+        lock.free_node_head = next_free_pos(lock, TreeFile::Node, node_pos, NODE_CHILD_META_OFFSET)?;
+        lock.n_free -= 1;
+        save_meta_data(lock)?;
+        node_pos
+    } else {
+        file_end(lock, TreeFile::Node)
+    };
+
     let node_data = NodeData {
         node_id: 0,
         node_pos,
@@ -287,124 +1498,192 @@ fn add_node(lock: &mut MutexGuard<FileData>, parent_pos: u64, hits: u64, score:
         first_child_pos: 0,
         n_children: 0,
         max_children,
+        children_sorted: false,
+        payload,
     };
-    let buf = node_to_buf(parent_pos, &node_data);
-    lock.node_file.write_all(&buf).map_err(|e| FileIOError {
-        msg: String::from(format!("while writing to node file: {}", e))
-    })?;
-    lock.n_nodes += 1;
+    write_at(lock, TreeFile::Node, node_pos, &node_to_buf(parent_pos, &node_data))?;
+    if pos_to_node_id(lock, node_pos) == lock.n_nodes {
+        lock.n_nodes += 1;
+    }
 
     Ok(node_pos)
 }
 
-fn update_node(lock: &mut MutexGuard<FileData>, node_data: &NodeData) -> Result<(), TreeFileError> {
-    lock.node_file.seek(SeekFrom::Start(node_data.node_pos)).unwrap();
+fn update_node<P: Payload, S: Score, St: Storage>(lock: &mut MutexGuard<FileData<St>>, node_data: &NodeData<P, S>) -> Result<(), TreeFileError> {
     let parent_pos = if let Some(p) = node_data.parent {
-        node_id_to_pos(p)
-    } else {u64::MAX};
+        node_id_to_pos(lock, p)
+    } else {NO_PARENT_POS};
 
-    let buf = node_to_buf(parent_pos, node_data);
-    lock.node_file.write_all(&buf).map_err(|e| FileIOError {
-        msg: String::from(format!("while writing to node file: {}", e))
-    })?;
+    write_at(lock, TreeFile::Node, node_data.node_pos, &node_to_buf(parent_pos, node_data))
+}
+
+fn update_nodes<P: Payload, S: Score, St: Storage>(lock: &mut MutexGuard<FileData<St>>, mut nodes: Vec<NodeData<P, S>>) -> Result<(), TreeFileError> {
+    nodes.sort_unstable_by_key(|nd| nd.node_pos);
+
+    let node_length = lock.node_length as u64;
+    let mut run_start: usize = 0;
+    while run_start < nodes.len() {
+        let mut buf: Vec<u8> = Vec::new();
+        let mut run_end = run_start;
+        while run_end < nodes.len() && nodes[run_end].node_pos == nodes[run_start].node_pos + (run_end - run_start) as u64 * node_length {
+            let parent_pos = nodes[run_end].parent.map_or(NO_PARENT_POS, |p| node_id_to_pos(lock, p));
+            buf.extend(node_to_buf(parent_pos, &nodes[run_end]));
+            run_end += 1;
+        }
+
+        write_at(lock, TreeFile::Node, nodes[run_start].node_pos, &buf)?;
+        run_start = run_end;
+    }
 
     Ok(())
 }
 
-fn expected_node_pos(lock: &mut MutexGuard<FileData>) -> u64 {
-    lock.node_file.seek(SeekFrom::End(0)).unwrap()
+fn expected_node_pos<St: Storage>(lock: &mut MutexGuard<FileData<St>>) -> u64 {
+    if lock.free_node_head != NO_FREE_POS {
+        lock.free_node_head
+    } else {
+        file_end(lock, TreeFile::Node)
+    }
 }
 
-fn get_node_child_meta(lock: &mut MutexGuard<FileData>, node_pos: u64) -> Result<ChildrenMeta, TreeFileError> {
-    lock.node_file.seek(SeekFrom::Start(node_pos + NODE_CHILD_META_OFFSET)).unwrap();
+fn get_node_child_meta<St: Storage>(lock: &mut MutexGuard<FileData<St>>, node_pos: u64) -> Result<ChildrenMeta, TreeFileError> {
     let mut buf = [0u8;NODE_CHILD_META_LENGTH];
-    lock.node_file.read_exact(&mut buf).map_err(|e| FileIOError {
-        msg: String::from(format!("while reading from node file: {}", e))
+    read_at(lock, TreeFile::Node, node_pos + NODE_CHILD_META_OFFSET, &mut buf).map_err(|e| FileIOError {
+        msg: format!("while reading from node file: {}", e)
     })?;
 
+    let n_children = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+
     Ok(ChildrenMeta{
         first_child_pos: u64::from_le_bytes(buf[0..8].try_into().unwrap()),
-        n_children: u32::from_le_bytes(buf[8..12].try_into().unwrap()),
+        n_children: n_children & !CHILDREN_SORTED_FLAG,
         max_children: u32::from_le_bytes(buf[12..16].try_into().unwrap()),
+        sorted: n_children & CHILDREN_SORTED_FLAG != 0,
     })
 }
 
-fn update_node_child_meta(lock: &mut MutexGuard<FileData>, node_pos: u64, children_meta: &ChildrenMeta) -> Result<(), TreeFileError> {
-    lock.node_file.seek(SeekFrom::Start(node_pos + NODE_CHILD_META_OFFSET)).unwrap();
-    let buf = node_children_to_buf(children_meta.first_child_pos, children_meta.n_children, children_meta.max_children);
-    lock.node_file.write_all(&buf).map_err(|e| FileIOError {
-        msg: String::from(format!("while writing to node file: {}", e))
-    })?;
+fn update_node_child_meta<St: Storage>(lock: &mut MutexGuard<FileData<St>>, node_pos: u64, children_meta: &ChildrenMeta) -> Result<(), TreeFileError> {
+    let buf = node_children_to_buf(children_meta.first_child_pos, children_len_with_flag(children_meta.n_children, children_meta.sorted), children_meta.max_children);
 
-    Ok(())
+    write_at(lock, TreeFile::Node, node_pos + NODE_CHILD_META_OFFSET, &buf)
 }
 
-fn get_children_maps(lock: &mut MutexGuard<FileData>, key: Option<u16>, children_meta: &ChildrenMeta) -> Result<ChildrenMaps, TreeFileError> {
-    lock.map_file.seek(SeekFrom::Start(children_meta.first_child_pos)).unwrap();
-    let mut buf = vec![0u8;MAP_LENGTH * children_meta.max_children as usize];
-    lock.map_file.read_exact(&mut buf).map_err(|e| FileIOError {
-        msg: String::from(format!("while reading from map file: {}", e))
-    })?;
+fn get_children_maps<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, key: Option<K>, children_meta: &ChildrenMeta) -> Result<ChildrenMaps<K>, TreeFileError> {
+    let buf = read_children_buf::<K, _>(lock, children_meta)?;
 
-    let mut child_no: usize = 0;
     let mut children_maps = ChildrenMaps { key_hit: None, child_maps: Vec::new() };
-    while child_no < children_meta.n_children as usize {
-        let offset = MAP_LENGTH * child_no;
-        let node_pos = u64::from_le_bytes(buf[0+offset..8+offset].try_into().unwrap());
-        let child_key = u16::from_le_bytes(buf[8+offset..10+offset].try_into().unwrap());
-        let node_id = pos_to_node_id(node_pos);
-        if let Some(k) = key {
-            if child_key == k {
-                children_maps.key_hit = Some(ChildMap{ node_id, node_pos, key: k });
-            }
+    for child_no in 0..children_meta.n_children as usize {
+        let child_map = child_map_from_buf(lock, &buf, child_no);
+        if key == Some(child_map.key) {
+            children_maps.key_hit = Some(child_map_from_buf(lock, &buf, child_no));
         }
 
-        children_maps.child_maps.push(ChildMap{ node_id, node_pos, key: child_key });
-        child_no += 1;
+        children_maps.child_maps.push(child_map);
     }
 
     Ok(children_maps)
 }
 
-fn update_children_maps(lock: &mut MutexGuard<FileData>, children_maps: Vec<ChildMap>, children_meta: &ChildrenMeta) -> Result<(), TreeFileError> {
-    lock.map_file.seek(SeekFrom::Start(children_meta.first_child_pos)).unwrap();
-    let buf = children_to_buf(children_maps, children_meta.max_children);
-    lock.map_file.write_all(&buf).map_err(|e| FileIOError {
-        msg: String::from(format!("while writing to map file: {}", e))
+fn find_child_map<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, key: K, children_meta: &ChildrenMeta) -> Result<Option<ChildMap<K>>, TreeFileError> {
+    let buf = read_children_buf::<K, _>(lock, children_meta)?;
+
+    // Blocks written before child maps were kept sorted have no sorted flag and must be scanned
+    if !children_meta.sorted {
+        return Ok((0..children_meta.n_children as usize)
+            .map(|child_no| child_map_from_buf(lock, &buf, child_no))
+            .find(|cm| cm.key == key));
+    }
+
+    let mut low: usize = 0;
+    let mut high = children_meta.n_children as usize;
+    while low < high {
+        let mid = low + (high - low) / 2;
+        let child_map = child_map_from_buf::<K, _>(lock, &buf, mid);
+        match child_map.key.cmp(&key) {
+            Ordering::Less => low = mid + 1,
+            Ordering::Greater => high = mid,
+            Ordering::Equal => return Ok(Some(child_map)),
+        }
+    }
+
+    Ok(None)
+}
+
+fn read_children_buf<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, children_meta: &ChildrenMeta) -> Result<Vec<u8>, TreeFileError> {
+    let mut buf = vec![0u8;map_length::<K>() * children_meta.n_children as usize];
+    read_at(lock, TreeFile::Map, children_meta.first_child_pos, &mut buf).map_err(|e| FileIOError {
+        msg: format!("while reading from map file: {}", e)
     })?;
 
+    Ok(buf)
+}
+
+fn child_map_from_buf<K: Key, St: Storage>(lock: &FileData<St>, buf: &[u8], child_no: usize) -> ChildMap<K> {
+    let offset = map_length::<K>() * child_no;
+    let node_pos = u64::from_le_bytes(buf[offset..MAP_NODE_POS_LENGTH+offset].try_into().unwrap());
+    let key = K::from_le_buf(&buf[MAP_NODE_POS_LENGTH+offset..]);
+
+    ChildMap{ node_id: pos_to_node_id(lock, node_pos), node_pos, key }
+}
+
+fn update_children_maps<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, children_maps: Vec<ChildMap<K>>, children_meta: &mut ChildrenMeta) -> Result<(), TreeFileError> {
+    let buf = children_to_buf(children_maps, children_meta.max_children);
+    write_at(lock, TreeFile::Map, children_meta.first_child_pos, &buf)?;
+    children_meta.sorted = true;
+
     Ok(())
 }
 
-fn add_child_map(lock: &mut MutexGuard<FileData>, child_map: ChildMap, max_children: u32) -> Result<u64, TreeFileError> {
-    let buf = children_to_buf(Vec::from([child_map]),max_children);
-    let children_pos = lock.map_file.seek(SeekFrom::End(0)).unwrap();
-    lock.map_file.write_all(&buf).map_err(|e| FileIOError {
-         msg: String::from(format!("while writing to map file: {}", e))
-    })?;
+fn add_children_maps<K: Key, St: Storage>(lock: &mut MutexGuard<FileData<St>>, children_maps: Vec<ChildMap<K>>, max_children: u32) -> Result<u64, TreeFileError> {
+    let buf = children_to_buf(children_maps, max_children);
+    let children_pos = match lock.free_map_heads.get(&max_children).copied() {
+        Some(children_pos) if children_pos != NO_FREE_POS => {
+            let next_pos = next_free_pos(lock, TreeFile::Map, children_pos, 0)?;
+            lock.free_map_heads.insert(max_children, next_pos);
+            save_meta_data(lock)?;
+            children_pos
+        },
+        _ => file_end(lock, TreeFile::Map),
+    };
+    write_at(lock, TreeFile::Map, children_pos, &buf)?;
 
     Ok(children_pos)
 }
 
-fn check_presence(lock: &mut MutexGuard<FileData>, node: NodeId) -> Result<(), TreeFileError> {
+fn check_presence<St: Storage>(lock: &mut MutexGuard<FileData<St>>, node: NodeId) -> Result<u64, TreeFileError> {
     if node >= lock.n_nodes {
+        return Err(NonExistingNode);
+    }
+
+    let node_pos = node_id_to_pos(lock, node);
+    let mut buf = [0u8;8];
+    read_at(lock, TreeFile::Node, node_pos, &mut buf).map_err(|e| FileIOError {
+        msg: format!("while reading from node file: {}", e)
+    })?;
+
+    if u64::from_le_bytes(buf) == FREE_NODE_POS {
         Err(NonExistingNode)
     } else {
-        Ok(())
+        Ok(node_pos)
     }
 }
 
-fn pos_to_node_id(pos: u64) -> NodeId {
-    (pos / NODE_LENGTH as u64) as NodeId
+fn map_length<K: Key>() -> usize {
+    MAP_NODE_POS_LENGTH + K::WIDTH
 }
 
-fn node_id_to_pos(node_id: NodeId) -> u64 {
-    node_id as u64 * NODE_LENGTH as u64
+fn pos_to_node_id<St: Storage>(lock: &FileData<St>, pos: u64) -> NodeId {
+    (pos.saturating_sub(HEADER_LENGTH as u64) / lock.node_length as u64) as NodeId
 }
 
-fn node_to_buf(parent_pos: u64, node_data: &NodeData) -> [u8;NODE_LENGTH] {
-    // |parent 8 |hits 8|score 8|children pos 8|children_len 4|max_children 4|
-    let mut buf = [0u8;NODE_LENGTH];
+fn node_id_to_pos<St: Storage>(lock: &FileData<St>, node_id: NodeId) -> u64 {
+    (HEADER_LENGTH + node_id * lock.node_length) as u64
+}
+
+fn node_to_buf<P: Payload, S: Score>(parent_pos: u64, node_data: &NodeData<P, S>) -> Vec<u8> {
+    // |parent 8 |hits 8|score 8|children pos 8|children_len 4|max_children 4|payload P::LENGTH|
+    // the top bit of children_len flags that the node's child maps are sorted by key
+    let mut buf = vec![0u8;NODE_META_LENGTH + P::LENGTH];
     let mut offset: usize = 0;
 
     parent_pos.to_le_bytes().iter().for_each(|v| {
@@ -415,15 +1694,13 @@ fn node_to_buf(parent_pos: u64, node_data: &NodeData) -> [u8;NODE_LENGTH] {
         buf[offset] = *v;
         offset += 1;
     });
-    node_data.score.to_le_bytes().iter().for_each(|v| {
-        buf[offset] = *v;
-        offset += 1;
-    });
+    node_data.score.to_le_buf(&mut buf[offset..]);
+    offset += 8;
     node_data.first_child_pos.to_le_bytes().iter().for_each(|v| {
         buf[offset] = *v;
         offset += 1;
     });
-    node_data.n_children.to_le_bytes().iter().for_each(|v| {
+    children_len_with_flag(node_data.n_children, node_data.children_sorted).to_le_bytes().iter().for_each(|v| {
         buf[offset] = *v;
         offset += 1;
     });
@@ -431,29 +1708,47 @@ fn node_to_buf(parent_pos: u64, node_data: &NodeData) -> [u8;NODE_LENGTH] {
         buf[offset] = *v;
         offset += 1;
     });
+    node_data.payload.encode(&mut buf[offset..]);
 
     buf
 }
 
-fn children_to_buf(children: Vec<ChildMap>, max_children: u32) -> Vec<u8> {
-    // |node 8|key 2| * max_children
-    let mut buf = vec![255u8;MAP_LENGTH * max_children as usize];
+fn children_to_buf<K: Key>(mut children: Vec<ChildMap<K>>, max_children: u32) -> Vec<u8> {
+    // |node 8|key K::WIDTH| * max_children, sorted by key
+    let mut buf = vec![255u8;map_length::<K>() * max_children as usize];
     let mut offset: usize = 0;
 
+    children.sort_unstable_by_key(|cm| cm.key);
     for child in children {
         child.node_pos.to_le_bytes().iter().for_each(|v| {
             buf[offset] = *v;
             offset += 1;
         });
-        child.key.to_le_bytes().iter().for_each(|v| {
-            buf[offset] = *v;
-            offset += 1;
-        });
+        child.key.to_le_buf(&mut buf[offset..]);
+        offset += K::WIDTH;
     }
 
     buf
 }
 
+fn children_len_with_flag(n_children: u32, sorted: bool) -> u32 {
+    if sorted {n_children | CHILDREN_SORTED_FLAG} else {n_children}
+}
+
+fn meta_to_buf(free_node_head: u64, n_free: usize, free_map_heads: &HashMap<u32, u64>) -> Vec<u8> {
+    // |free node head 8|n free 8|n classes 4| + |max_children 4|free map head 8| * n classes
+    let mut buf: Vec<u8> = Vec::new();
+    free_node_head.to_le_bytes().iter().for_each(|v| buf.push(*v));
+    (n_free as u64).to_le_bytes().iter().for_each(|v| buf.push(*v));
+    (free_map_heads.len() as u32).to_le_bytes().iter().for_each(|v| buf.push(*v));
+    free_map_heads.iter().for_each(|(max_children, head)| {
+        max_children.to_le_bytes().iter().for_each(|v| buf.push(*v));
+        head.to_le_bytes().iter().for_each(|v| buf.push(*v));
+    });
+
+    buf
+}
+
 fn node_children_to_buf(children_pos: u64, children_len: u32, children_max: u32) -> [u8;NODE_CHILD_META_LENGTH] {
     // |children 8 |children_len 4|max_children 4|
     let mut buf = [0u8;NODE_CHILD_META_LENGTH];
@@ -26,7 +26,7 @@ pub fn open_file(path: &str) -> Result<File, TreeFileError> {
 
 pub fn add_and_subtract(mut value: u64, add: i64) -> Result<u64, TreeFileError> {
     if add < 0 {
-        let a = add.abs() as u64;
+        let a = add.unsigned_abs();
         if a > value {
             return Err(LogicError {
                 msg: String::from("Would subtract below zero on unsigned value (u64)")
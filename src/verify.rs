@@ -0,0 +1,13 @@
+use crate::NodeId;
+use crate::key::Key;
+
+#[derive(Debug, PartialEq)]
+pub enum Inconsistency<K: Key = u16> {
+    PartialNodeRecord {file_length: u64},
+    PartialMapRecord {file_length: u64},
+    BadParent {node: NodeId},
+    TooManyChildren {node: NodeId, n_children: u32, max_children: u32},
+    ChildMapOutOfBounds {node: NodeId},
+    DanglingChildMap {node: NodeId, key: K},
+    Orphan {node: NodeId},
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+const WAL_COUNT_LENGTH: usize = 4;
+const WAL_RECORD_HEADER_LENGTH: usize = 13;
+const WAL_CHECKSUM_LENGTH: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum TreeFile {
+    Node,
+    Map,
+    Meta,
+}
+
+impl TreeFile {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            TreeFile::Node => "node",
+            TreeFile::Map => "map",
+            TreeFile::Meta => "meta",
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<TreeFile> {
+        match v {
+            0 => Some(TreeFile::Node),
+            1 => Some(TreeFile::Map),
+            2 => Some(TreeFile::Meta),
+            _ => None,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            TreeFile::Node => 0,
+            TreeFile::Map => 1,
+            TreeFile::Meta => 2,
+        }
+    }
+}
+
+pub(crate) struct WalRecord {
+    pub(crate) tree_file: TreeFile,
+    pub(crate) pos: u64,
+    pub(crate) data: Vec<u8>,
+}
+
+// Writes held back during a transaction. Each file keeps them as runs that neither overlap nor touch,
+// so later reads in the same transaction can see them and adjacent writes leave as one record. A
+// file that is truncated also keeps its new length, which leaves as a record without data
+#[derive(Default)]
+pub(crate) struct PendingWrites {
+    runs: [BTreeMap<u64, Vec<u8>>; 3],
+    lens: [Option<u64>; 3],
+}
+
+impl PendingWrites {
+    pub(crate) fn write(&mut self, tree_file: TreeFile, pos: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let runs = &mut self.runs[tree_file.to_u8() as usize];
+        let end = pos + data.len() as u64;
+        if let Some(len) = self.lens[tree_file.to_u8() as usize].as_mut() {
+            *len = (*len).max(end);
+        }
+
+        // Runs that overlap or touch the new data are merged with it, the lowest one is grown in place
+        let mut touching = runs.range(..=end).rev()
+            .take_while(|(&start, run)| start + run.len() as u64 >= pos)
+            .map(|(&start, _)| start)
+            .collect::<Vec<u64>>();
+        touching.reverse();
+
+        let (start, mut merged) = match touching.first() {
+            Some(&first) if first <= pos => (first, runs.remove(&first).unwrap()),
+            _ => (pos, Vec::new()),
+        };
+        for run_start in touching.into_iter().filter(|&run_start| run_start != start) {
+            let run = runs.remove(&run_start).unwrap();
+            copy_into(&mut merged, (run_start - start) as usize, &run);
+        }
+        copy_into(&mut merged, (pos - start) as usize, data);
+        runs.insert(start, merged);
+    }
+
+    pub(crate) fn set_len(&mut self, tree_file: TreeFile, len: u64) {
+        let runs = &mut self.runs[tree_file.to_u8() as usize];
+        for run_start in runs.range(len..).map(|(&start, _)| start).collect::<Vec<u64>>() {
+            runs.remove(&run_start);
+        }
+        if let Some((&start, run)) = runs.last_key_value() {
+            if start + run.len() as u64 > len {
+                runs.get_mut(&start).unwrap().truncate((len - start) as usize);
+            }
+        }
+        self.lens[tree_file.to_u8() as usize] = Some(len);
+    }
+
+    pub(crate) fn len(&self, tree_file: TreeFile) -> Option<u64> {
+        self.lens[tree_file.to_u8() as usize]
+    }
+
+    pub(crate) fn read(&self, tree_file: TreeFile, pos: u64, buf: &mut [u8]) {
+        let end = pos + buf.len() as u64;
+        for (&start, run) in self.runs[tree_file.to_u8() as usize].range(..end).rev() {
+            let run_end = start + run.len() as u64;
+            if run_end <= pos {
+                break;
+            }
+            let (from, to) = (start.max(pos), run_end.min(end));
+            buf[(from - pos) as usize..(to - pos) as usize].copy_from_slice(&run[(from - start) as usize..(to - start) as usize]);
+        }
+    }
+
+    pub(crate) fn end(&self, tree_file: TreeFile) -> u64 {
+        self.runs[tree_file.to_u8() as usize].last_key_value().map_or(0, |(&start, run)| start + run.len() as u64)
+    }
+
+    pub(crate) fn into_records(self) -> Vec<WalRecord> {
+        let [node_runs, map_runs, meta_runs] = self.runs;
+        [(TreeFile::Node, node_runs), (TreeFile::Map, map_runs), (TreeFile::Meta, meta_runs)].into_iter()
+            .zip(self.lens)
+            .flat_map(|((tree_file, runs), len)| runs.into_iter()
+                .chain(len.map(|len| (len, Vec::new())))
+                .map(move |(pos, data)| WalRecord { tree_file, pos, data }))
+            .collect()
+    }
+}
+
+fn copy_into(buf: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    if buf.len() < offset + data.len() {
+        buf.resize(offset + data.len(), 0);
+    }
+    buf[offset..offset + data.len()].copy_from_slice(data);
+}
+
+pub(crate) fn records_to_buf(records: &[WalRecord]) -> Vec<u8> {
+    // |n records 4| + |file 1|pos 8|len 4|data len| * n records + |checksum 8|, a record without data
+    // sets the file length to its pos
+    let mut buf: Vec<u8> = Vec::new();
+    (records.len() as u32).to_le_bytes().iter().for_each(|v| buf.push(*v));
+    for record in records {
+        buf.push(record.tree_file.to_u8());
+        record.pos.to_le_bytes().iter().for_each(|v| buf.push(*v));
+        (record.data.len() as u32).to_le_bytes().iter().for_each(|v| buf.push(*v));
+        buf.extend_from_slice(&record.data);
+    }
+    checksum(&buf).to_le_bytes().iter().for_each(|v| buf.push(*v));
+
+    buf
+}
+
+pub(crate) fn records_from_buf(buf: &[u8]) -> Option<Vec<WalRecord>> {
+    // A log that is cut short or does not match its checksum was never committed and is ignored
+    if buf.len() < WAL_COUNT_LENGTH + WAL_CHECKSUM_LENGTH {
+        return None;
+    }
+    let body_len = buf.len() - WAL_CHECKSUM_LENGTH;
+    if checksum(&buf[..body_len]) != u64::from_le_bytes(buf[body_len..].try_into().unwrap()) {
+        return None;
+    }
+
+    let n_records = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    let mut records: Vec<WalRecord> = Vec::new();
+    let mut offset = WAL_COUNT_LENGTH;
+    for _ in 0..n_records {
+        if offset + WAL_RECORD_HEADER_LENGTH > body_len {
+            return None;
+        }
+        let tree_file = TreeFile::from_u8(buf[offset])?;
+        let pos = u64::from_le_bytes(buf[offset+1..offset+9].try_into().unwrap());
+        let len = u32::from_le_bytes(buf[offset+9..offset+13].try_into().unwrap()) as usize;
+        offset += WAL_RECORD_HEADER_LENGTH;
+        if offset + len > body_len {
+            return None;
+        }
+        records.push(WalRecord { tree_file, pos, data: buf[offset..offset+len].to_vec() });
+        offset += len;
+    }
+
+    Some(records)
+}
+
+fn checksum(buf: &[u8]) -> u64 {
+    // FNV-1a
+    buf.iter().fold(FNV_OFFSET, |hash, v| (hash ^ *v as u64).wrapping_mul(FNV_PRIME))
+}
@@ -1,17 +1,20 @@
 use std::collections::HashMap;
-use std::fs::{read_dir, remove_file};
+use std::fs::{create_dir_all, read_dir, remove_file, write};
+use rust_tree_map::migration::migrate_multi_file_tree_map;
 use rust_tree_map::multi_file_tree_map::MultiFileTreeMap;
-use rust_tree_map::NodeId;
+use rust_tree_map::{NodeId, TreeFileError};
+use rust_tree_map::key::Key;
 use rust_tree_map::OpenMode::{TruncateCreate, OpenCreate, MustExist};
+use rust_tree_map::policy::SelectionPolicy::{MaxMean, MaxVisits};
 
 const MAP_PATH: &str = "tests/test_data";
 
-fn remove_files<F>(tree_map: MultiFileTreeMap<F>)
-    where F: Fn(u16) -> u8
+fn remove_files<F, K>(tree_map: MultiFileTreeMap<F, K>, path: &str)
+    where F: Fn(K) -> u8, K: Key
 {
     drop(tree_map);
 
-    for entry in read_dir(MAP_PATH).unwrap() {
+    for entry in read_dir(path).unwrap() {
         let entry = entry.unwrap();
         let path = entry.path();
         if path.is_file() {
@@ -20,12 +23,19 @@ fn remove_files<F>(tree_map: MultiFileTreeMap<F>)
     }
 }
 
+fn test_path(name: &str) -> String {
+    let path = format!("{}/{}", MAP_PATH, name);
+    create_dir_all(&path).unwrap();
+    path
+}
+
 #[test]
 fn create_a_new_tree() {
+    let path = test_path("create_a_new_tree");
     let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
     //let key1 = ((10 << 8) + 1) as u16;
 
-    let mut res = MultiFileTreeMap::new(MAP_PATH, 2, TruncateCreate, splitter);
+    let mut res = MultiFileTreeMap::new(&path, 2, TruncateCreate, splitter);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -45,19 +55,20 @@ fn create_a_new_tree() {
         }
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 }
 
 #[test]
 fn open_existing_tree() {
+    let path = test_path("open_existing_tree");
     let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
 
-    let res = MultiFileTreeMap::new(MAP_PATH, 2, TruncateCreate, splitter);
+    let res = MultiFileTreeMap::new(&path, 2, TruncateCreate, splitter);
     assert!(res.is_ok(), "tree not created");
 
     drop(res.unwrap());
 
-    let mut res = MultiFileTreeMap::new(MAP_PATH, 10, OpenCreate, splitter);
+    let mut res = MultiFileTreeMap::new(&path, 10, OpenCreate, splitter);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -76,7 +87,7 @@ fn open_existing_tree() {
 
     drop(res.unwrap());
 
-    let mut res = MultiFileTreeMap::new(MAP_PATH, 10, MustExist, splitter);
+    let mut res = MultiFileTreeMap::new(&path, 10, MustExist, splitter);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -93,15 +104,16 @@ fn open_existing_tree() {
         }
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 
-    let res = MultiFileTreeMap::new(MAP_PATH, 10, MustExist, splitter);
+    let res = MultiFileTreeMap::new(&path, 10, MustExist, splitter);
     assert!(res.is_err(), "tree created");
 
 }
 
 #[test]
 fn can_add_children() {
+    let path = test_path("can_add_children");
     let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
     let key1 = ((10 << 8) + 1) as u16;
     let key2 = ((15 << 8) + 1) as u16;
@@ -110,7 +122,7 @@ fn can_add_children() {
     let key5 = ((40 << 8) + 1) as u16;
     let key6 = ((50 << 8) + 1) as u16;
 
-    let mut res = MultiFileTreeMap::new(MAP_PATH, 2, TruncateCreate, splitter);
+    let mut res = MultiFileTreeMap::new(&path, 2, TruncateCreate, splitter);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -164,17 +176,18 @@ fn can_add_children() {
         assert_eq!(t.len(), 5, "should be 5, one top and 4 in child trees");
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 }
 
 #[test]
 fn can_get_children() {
+    let path = test_path("can_get_children");
     let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
     let key1 = ((10 << 8) + 1) as u16;
     let key2 = ((15 << 8) + 1) as u16;
     let key3 = ((20 << 8) + 1) as u16;
 
-    let mut res = MultiFileTreeMap::new(MAP_PATH, 3, TruncateCreate, splitter);
+    let mut res = MultiFileTreeMap::new(&path, 3, TruncateCreate, splitter);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -211,16 +224,17 @@ fn can_get_children() {
         assert_eq!(comp.len(), 0, "iterator should have returned all children");
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 }
 
 #[test]
 fn can_get_none_for_get_child_with_no_file() {
+    let path = test_path("can_get_none_for_get_child_with_no_file");
     let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
     let key1 = ((10 << 8) + 1) as u16;
     let key2 = ((15 << 8) + 1) as u16;
 
-    let mut res = MultiFileTreeMap::new(MAP_PATH, 2, TruncateCreate, splitter);
+    let mut res = MultiFileTreeMap::new(&path, 2, TruncateCreate, splitter);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -231,9 +245,7 @@ fn can_get_none_for_get_child_with_no_file() {
         let child2_nd = t.get_child(t.get_top(), key2);
         assert!(child2_nd.is_ok(), "should not return error");
 
-        if let Some(_nd) = child2_nd.unwrap() {
-            assert!(false, "should not return data");
-        }
+        assert!(child2_nd.unwrap().is_none(), "should not return data");
 
         let child2 = t.add_child(t.get_top(), key2, 200, 2000, 2).unwrap();
         assert_eq!(child2, 271, "second child shall get node id 271");
@@ -242,22 +254,21 @@ fn can_get_none_for_get_child_with_no_file() {
         let child2_nd = t.get_child(t.get_top(), key2);
         assert!(res.is_ok(), "should not return error");
 
-        if let None = child2_nd.unwrap() {
-            assert!(false, "should not return none");
-        }
+        assert!(child2_nd.unwrap().is_some(), "should not return none");
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 }
 
 #[test]
 fn can_get_node() {
+    let path = test_path("can_get_node");
     let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
     let key1 = ((10 << 8) + 1) as u16;
     let key2 = ((15 << 8) + 1) as u16;
     let key3 = ((20 << 8) + 1) as u16;
 
-    let mut res = MultiFileTreeMap::new(MAP_PATH, 3, TruncateCreate, splitter);
+    let mut res = MultiFileTreeMap::new(&path, 3, TruncateCreate, splitter);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -279,17 +290,18 @@ fn can_get_node() {
         }
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 }
 
 #[test]
 fn can_get_parent() {
+    let path = test_path("can_get_parent");
     let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
     let key1 = ((10 << 8) + 1) as u16;
     let key2 = ((15 << 8) + 1) as u16;
     let key3 = ((20 << 8) + 1) as u16;
 
-    let mut res = MultiFileTreeMap::new(MAP_PATH, 3, TruncateCreate, splitter);
+    let mut res = MultiFileTreeMap::new(&path, 3, TruncateCreate, splitter);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -312,17 +324,18 @@ fn can_get_parent() {
         }
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 }
 
 #[test]
 fn can_update_add_node() {
+    let path = test_path("can_update_add_node");
     let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
     let key1 = ((10 << 8) + 1) as u16;
     let key2 = ((15 << 8) + 1) as u16;
     let key3 = ((20 << 8) + 1) as u16;
 
-    let mut res = MultiFileTreeMap::new(MAP_PATH, 3, TruncateCreate, splitter);
+    let mut res = MultiFileTreeMap::new(&path, 3, TruncateCreate, splitter);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -354,5 +367,276 @@ fn can_update_add_node() {
 
     }
 
-    remove_files(res.unwrap());
-}
\ No newline at end of file
+    remove_files(res.unwrap(), &path);
+}
+#[test]
+fn can_set_max_children() {
+    let path = test_path("can_set_max_children");
+    let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
+    let key1 = ((10 << 8) + 1) as u16;
+    let key2 = ((10 << 8) + 2) as u16;
+    let key3 = ((10 << 8) + 3) as u16;
+
+    let mut res = MultiFileTreeMap::new(&path, 2, TruncateCreate, splitter);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), key1, 100, 1000, 1).unwrap();
+        let _child11 = t.add_child(child1, key2, 10, 100, 1).unwrap();
+        assert!(t.add_child(child1, key3, 10, 100, 1).is_err(), "second sub child shall fail");
+
+        assert!(t.set_max_children(t.get_top(), 5).is_err(), "shall not set max children on virtual top node");
+        assert!(t.set_max_children(child1, 2).is_ok(), "could not grow max children");
+        assert!(t.add_child(child1, key3, 10, 100, 1).is_ok(), "second sub child shall go ok after growing");
+
+        let nd = t.get_node(child1).unwrap();
+        assert_eq!(nd.max_children, 2, "should have max children 2");
+        assert_eq!(nd.n_children, 2, "should have 2 children");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_use_other_key_types() {
+    let path = test_path("can_use_other_key_types");
+    let splitter = |k: u32| {(k >> 24) as u8};
+    let key1 = (10 << 24) + 70_000;
+    let key2 = (10 << 24) + 80_000;
+
+    let res = MultiFileTreeMap::open(&path, 2, TruncateCreate, splitter);
+    assert!(res.is_ok(), "tree not created");
+
+    let mut t = res.unwrap();
+    let child1 = t.add_child(t.get_top(), key1, 100, 1000, 2).unwrap();
+    assert_eq!(child1, 266, "first child shall get node id 266");
+
+    let child11 = t.add_child(child1, key2, 10, 100, 2).unwrap();
+    let nd = t.get_child(child1, key2).unwrap();
+    assert_eq!(nd.map(|n| n.node_id), Some(child11), "could not get sub child via 32 bit key");
+
+    let children = t.get_child_iter(t.get_top()).collect::<Vec<(u32, NodeId)>>();
+    assert_eq!(children, vec![(key1, child1)], "iterator should return 32 bit keys");
+
+    remove_files(t, &path);
+}
+
+#[test]
+fn can_backpropagate() {
+    let path = test_path("can_backpropagate");
+    let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
+    let key1 = ((10 << 8) + 1) as u16;
+    let key2 = ((20 << 8) + 1) as u16;
+
+    let res = MultiFileTreeMap::new(&path, 2, TruncateCreate, splitter);
+    assert!(res.is_ok(), "tree not created");
+
+    let mut t = res.unwrap();
+    let child1 = t.add_child(t.get_top(), key1, 0, 10, 2).unwrap();
+    let child2 = t.add_child(t.get_top(), key2, 0, 10, 2).unwrap();
+    let child11 = t.add_child(child1, key1, 0, 10, 2).unwrap();
+
+    let n = t.backpropagate(child11, 1, 2, false).unwrap();
+    assert_eq!(n, 3, "should update 3 nodes, got {}", n);
+    let n = t.backpropagate(child11, 1, 1, true).unwrap();
+    assert_eq!(n, 3, "should update 3 nodes, got {}", n);
+
+    let nd = t.get_node(child11).unwrap();
+    assert_eq!((nd.hits, nd.score), (2, 13), "wrong leaf values");
+    let nd = t.get_node(child1).unwrap();
+    assert_eq!((nd.hits, nd.score), (2, 11), "sign should be flipped on the leaf's parent");
+    let nd = t.get_node(t.get_top()).unwrap();
+    assert_eq!((nd.hits, nd.score), (2, 3), "master top counters should be updated");
+    let nd = t.get_node(child2).unwrap();
+    assert_eq!((nd.hits, nd.score), (0, 10), "nodes off the path should not be updated");
+
+    assert!(t.backpropagate(child1, 0, -4, false).is_err(), "should fail when the top score goes below zero");
+    let nd = t.get_node(child1).unwrap();
+    assert_eq!(nd.score, 11, "a failing backpropagation should not update the tree file");
+
+    let n = t.backpropagate(t.get_top(), 1, 1, false).unwrap();
+    assert_eq!(n, 1, "should only update the top node, got {}", n);
+    drop(t);
+
+    let mut t = MultiFileTreeMap::new(&path, 2, MustExist, splitter).unwrap();
+    let nd = t.get_node(t.get_top()).unwrap();
+    assert_eq!((nd.hits, nd.score), (3, 4), "master top counters should be persisted");
+
+    remove_files(t, &path);
+}
+
+#[test]
+fn can_get_and_insert_paths() {
+    let path = test_path("can_get_and_insert_paths");
+    let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
+    let key1 = ((10 << 8) + 1) as u16;
+    let key2 = ((20 << 8) + 1) as u16;
+
+    let res = MultiFileTreeMap::new(&path, 2, TruncateCreate, splitter);
+    assert!(res.is_ok(), "tree not created");
+
+    let mut t = res.unwrap();
+    assert_eq!(t.get_path(&[key1]).unwrap(), None, "should not find a path in a non existing tree file");
+    assert_eq!(t.get_path_prefix(&[key1, key2]).unwrap(), (t.get_top(), 0), "prefix in a non existing tree file should be the top node");
+
+    let leaf = t.insert_path(&[key1, key2, key1], 1, 10, 2).unwrap();
+    assert_eq!(t.len(), 4, "should have 4 nodes, got {}", t.len());
+    assert_eq!(t.get_path(&[]).unwrap(), Some(t.get_top()), "empty path should give the top node");
+    assert_eq!(t.get_path(&[key1, key2, key1]).unwrap(), Some(leaf), "could not get inserted path");
+
+    let child1 = t.get_child(t.get_top(), key1).unwrap().unwrap().node_id;
+    assert_eq!(t.get_path_prefix(&[key1, key1]).unwrap(), (child1, 1), "wrong prefix for partly existing path");
+    assert_eq!(t.get_path_prefix(&[key1, key2, key1]).unwrap(), (leaf, 3), "wrong prefix for existing path");
+
+    let nd = t.get_node(leaf).unwrap();
+    assert_eq!((nd.hits, nd.score), (1, 10), "new nodes should get the given defaults");
+    assert_eq!(t.get_parent(child1).unwrap().map(|n| n.node_id), Some(t.get_top()), "first node on the path should have the top node as parent");
+
+    assert_eq!(t.insert_path(&[key1, key2, key1], 1, 10, 2).unwrap(), leaf, "inserting an existing path should give its leaf");
+    assert_eq!(t.len(), 4, "inserting an existing path should not add nodes");
+
+    assert!(t.insert_path(&[key2, key1], 1, 10, 0).is_err(), "should fail when a new tree file can not take children");
+    assert!(t.insert_path(&[key1, key2, key1, key2, key1], 1, 10, 0).is_err(), "should fail when a new node on the path can not take children");
+    assert_eq!(t.len(), 4, "a failing path insert should not add nodes, got {}", t.len());
+    assert_eq!(t.get_path_prefix(&[key1, key2, key1, key2]).unwrap(), (leaf, 3), "node of a failing path insert was added");
+    assert!(t.insert_path(&[key2], 1, 10, 2).is_ok(), "a failing path insert should not use up a tree file");
+
+    remove_files(t, &path);
+}
+
+#[test]
+fn can_traverse_depth_and_breadth_first() {
+    let path = test_path("can_traverse_depth_and_breadth_first");
+    let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
+    let key1 = ((10 << 8) + 1) as u16;
+    let key2 = ((20 << 8) + 1) as u16;
+
+    let res = MultiFileTreeMap::new(&path, 2, TruncateCreate, splitter);
+    assert!(res.is_ok(), "tree not created");
+
+    let mut t = res.unwrap();
+    let leaf1 = t.insert_path(&[key1, 1, 2], 1, 0, 2).unwrap();
+    let leaf2 = t.insert_path(&[key2, 1], 1, 0, 2).unwrap();
+
+    let keys = t.dfs(t.get_top())
+        .map(|r| r.map(|(d, k, _)| (d, k)))
+        .collect::<Result<Vec<(usize, Option<u16>)>, _>>().unwrap();
+    assert_eq!(keys, vec![(0, None), (1, Some(key1)), (2, Some(1)), (3, Some(2)), (1, Some(key2)), (2, Some(1))], "wrong depth first order");
+
+    let nodes = t.bfs(t.get_top())
+        .map(|r| r.map(|(_, _, nd)| nd.node_id))
+        .collect::<Result<Vec<NodeId>, _>>().unwrap();
+    assert_eq!(nodes.len(), 6, "should visit all nodes, got {}", nodes.len());
+    assert_eq!(nodes[0], t.get_top(), "should start at the top node");
+    assert_eq!(nodes[4..], [leaf2, leaf1], "deepest node should come last");
+
+    let n = t.bfs(t.get_top()).max_depth(1).count();
+    assert_eq!(n, 3, "should stop at max depth 1, got {} nodes", n);
+
+    remove_files(t, &path);
+}
+
+#[test]
+fn can_get_principal_variation() {
+    let path = test_path("can_get_principal_variation");
+    let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
+    let key1 = ((10 << 8) + 1) as u16;
+    let key2 = ((20 << 8) + 1) as u16;
+
+    let res = MultiFileTreeMap::new(&path, 2, TruncateCreate, splitter);
+    assert!(res.is_ok(), "tree not created");
+
+    let mut t = res.unwrap();
+    assert!(t.principal_variation(t.get_top(), MaxVisits, 10).unwrap().is_empty(), "should be empty without children");
+
+    let leaf1 = t.insert_path(&[key1, 1, 2], 5, 5, 2).unwrap();
+    t.insert_path(&[key2, 1], 8, 1, 2).unwrap();
+
+    let pv = t.principal_variation(t.get_top(), MaxVisits, 10).unwrap();
+    let keys = pv.iter().map(|(k, _)| *k).collect::<Vec<u16>>();
+    assert_eq!(keys, vec![key2, 1], "wrong principal variation by visits");
+
+    let pv = t.principal_variation(t.get_top(), MaxMean, 10).unwrap();
+    let keys = pv.iter().map(|(k, _)| *k).collect::<Vec<u16>>();
+    assert_eq!(keys, vec![key1, 1, 2], "wrong principal variation by mean score");
+    assert_eq!(pv[2].1.node_id, leaf1, "node ids should be multi file node ids");
+
+    let child1 = pv[0].1.node_id;
+    let pv = t.principal_variation(child1, MaxMean, 1).unwrap();
+    assert_eq!(pv.len(), 1, "principal variation should stop at max length");
+    assert_eq!(pv[0].0, 1, "wrong principal variation from a given start node");
+
+    remove_files(t, &path);
+}
+
+#[test]
+fn rejects_incompatible_files() {
+    let path = test_path("multi_rejects_incompatible_files");
+    let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
+    let key1 = ((10 << 8) + 1) as u16;
+
+    let res = MultiFileTreeMap::new(&path, 2, TruncateCreate, splitter);
+    assert!(res.is_ok(), "tree not created");
+    let mut t = res.unwrap();
+    t.add_child(t.get_top(), key1, 1, 1, 2).unwrap();
+    drop(t);
+
+    let splitter32: fn(u32) -> u8 = |k| {(k >> 24) as u8};
+    let res = MultiFileTreeMap::open(&path, 2, MustExist, splitter32);
+    assert!(matches!(res, Err(TreeFileError::IncompatibleFormat {..})), "should not open with another key width");
+    drop(res);
+
+    let res = MultiFileTreeMap::new(&path, 2, MustExist, splitter);
+    assert!(res.is_ok(), "tree not opened");
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn migrates_legacy_files() {
+    let path = test_path("multi_migrates_legacy_files");
+    let dest_path = test_path("multi_migrates_legacy_files_dest");
+    let splitter: fn(u16) -> u8 = |k| {(k >> 8) as u8};
+    let key = |k: u16| (10 << 8) + k;
+
+    // Version 0.3 layout without headers, a master file with one tree whose top has three children
+    let mut master: Vec<u8> = Vec::new();
+    master.extend(2u32.to_le_bytes());
+    master.extend(1u32.to_le_bytes());
+    master.extend(60u64.to_le_bytes());
+    master.extend(120u64.to_le_bytes());
+    master.push(10);
+    write(format!("{}/multifile_treemap.bin", path), master).unwrap();
+
+    let mut nodes: Vec<u8> = Vec::new();
+    for (parent, hits, n_children, max_children) in [(u64::MAX, 60u64, 3u32, 4u32), (0, 20, 0, 2), (0, 10, 0, 2), (0, 30, 0, 2)] {
+        [parent, hits, hits * 2, 0].iter().for_each(|v| nodes.extend(v.to_le_bytes()));
+        nodes.extend(n_children.to_le_bytes());
+        nodes.extend(max_children.to_le_bytes());
+    }
+    let mut map: Vec<u8> = Vec::new();
+    for (node_id, k) in [(1u64, 20u16), (2, 10), (3, 30)] {
+        map.extend((node_id * 40).to_le_bytes());
+        map.extend(key(k).to_le_bytes());
+    }
+    map.extend([255u8;10]);
+    write(format!("{}/010.treemap.nodes.bin", path), nodes).unwrap();
+    write(format!("{}/010.treemap.map.bin", path), map).unwrap();
+
+    assert!(migrate_multi_file_tree_map(&path, Some(&dest_path)).unwrap(), "files not migrated to new directory");
+    assert!(migrate_multi_file_tree_map(&path, None).unwrap(), "files not migrated in place");
+    assert!(!migrate_multi_file_tree_map(&path, None).unwrap(), "migrated files should not be migrated again");
+
+    for p in [&path, &dest_path] {
+        let res = MultiFileTreeMap::new(p, 2, MustExist, splitter);
+        assert!(res.is_ok(), "migrated tree not opened");
+
+        let mut t = res.unwrap();
+        let top = t.get_node(t.get_top()).unwrap();
+        assert_eq!((top.hits, top.score), (60, 120), "master data not kept by migration");
+        for (k, hits) in [(10, 10), (20, 20), (30, 30)] {
+            let nd = t.get_child(t.get_top(), key(k)).unwrap();
+            assert_eq!(nd.map(|n| n.hits), Some(hits), "could not get child with key {} after migration", key(k));
+        }
+        remove_files(t, p);
+    }
+}
@@ -1,16 +1,22 @@
 use std::collections::HashMap;
-use std::fs::{read_dir, remove_file};
-use rust_tree_map;
-use rust_tree_map::NodeId;
+use std::fs::{copy, create_dir_all, metadata, read, read_dir, remove_file, write};
+use rust_tree_map::batch::WriteBatch;
+use rust_tree_map::{NodeId, TreeFileError};
+use rust_tree_map::migration::{is_legacy_tree_map, migrate_tree_map};
 use rust_tree_map::OpenMode::{MustExist, OpenCreate, TruncateCreate};
-use rust_tree_map::tree_map::TreeMap;
+use rust_tree_map::payload::Payload;
+use rust_tree_map::policy::SelectionPolicy::{MaxMean, MaxVisits, Puct, Uct};
+use rust_tree_map::storage::{FileStorage, MemStorage, MmapStorage, Storage};
+use rust_tree_map::tree_map::{MemTreeMap, TreeMap};
+use rust_tree_map::verify::Inconsistency;
 
 const MAP_PATH: &str = "tests/test_data";
+const HEADER_LENGTH: usize = 32;
 
-fn remove_files(tree_map: TreeMap) {
+fn remove_files(tree_map: TreeMap, path: &str) {
     drop(tree_map);
 
-    for entry in read_dir(MAP_PATH).unwrap() {
+    for entry in read_dir(path).unwrap() {
         let entry = entry.unwrap();
         let path = entry.path();
         if path.is_file() {
@@ -19,9 +25,16 @@ fn remove_files(tree_map: TreeMap) {
     }
 }
 
+fn test_path(name: &str) -> String {
+    let path = format!("{}/{}", MAP_PATH, name);
+    create_dir_all(&path).unwrap();
+    path
+}
+
 #[test]
 fn creates_a_new_tree() {
-    let res = TreeMap::new(MAP_PATH, 2, TruncateCreate, None);
+    let path = test_path("creates_a_new_tree");
+    let res = TreeMap::new(&path, 2, TruncateCreate, None);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref t) = res {
@@ -29,21 +42,22 @@ fn creates_a_new_tree() {
         assert_eq!(t.get_top(), 0, "top node shall always have node id 0 (zero)");
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 
-    let res = TreeMap::new(MAP_PATH, 2, OpenCreate, None);
+    let res = TreeMap::new(&path, 2, OpenCreate, None);
     assert!(res.is_ok(), "tree not created");
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 
-    let res = TreeMap::new(MAP_PATH, 2, MustExist, None);
+    let res = TreeMap::new(&path, 2, MustExist, None);
     assert!(res.is_err(), "tree created");
 
 }
 
 #[test]
 fn can_add_children() {
-    let mut res = TreeMap::new(MAP_PATH, 2, TruncateCreate, None);
+    let path = test_path("can_add_children");
+    let mut res = TreeMap::new(&path, 2, TruncateCreate, None);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -57,12 +71,13 @@ fn can_add_children() {
         assert!(child3.is_err(), "third child shall fail");
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 }
 
 #[test]
 fn can_get_children() {
-    let mut res = TreeMap::new(MAP_PATH, 3, TruncateCreate, None);
+    let path = test_path("can_get_children");
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -99,12 +114,13 @@ fn can_get_children() {
         assert_eq!(comp.len(), 0, "iterator should have returned all children, but omitted {}", comp.len());
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 }
 
 #[test]
 fn can_get_node() {
-    let mut res = TreeMap::new(MAP_PATH, 3, TruncateCreate, None);
+    let path = test_path("can_get_node");
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -126,12 +142,13 @@ fn can_get_node() {
         }
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 }
 
 #[test]
 fn can_get_parent() {
-    let mut res = TreeMap::new(MAP_PATH, 3, TruncateCreate, None);
+    let path = test_path("can_get_parent");
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -154,12 +171,13 @@ fn can_get_parent() {
         }
     }
 
-    remove_files(res.unwrap());
+    remove_files(res.unwrap(), &path);
 }
 
 #[test]
 fn can_update_add_node() {
-    let mut res = TreeMap::new(MAP_PATH, 3, TruncateCreate, None);
+    let path = test_path("can_update_add_node");
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
     assert!(res.is_ok(), "tree not created");
 
     if let Ok(ref mut t) = res {
@@ -179,5 +197,1358 @@ fn can_update_add_node() {
         }
     }
 
-    remove_files(res.unwrap());
-}
\ No newline at end of file
+    remove_files(res.unwrap(), &path);
+}
+#[test]
+fn can_remove_child() {
+    let path = test_path("can_remove_child");
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 10, 100, 1000, 2).unwrap();
+        let _child2 = t.add_child(t.get_top(), 15, 200, 2000, 2).unwrap();
+        let child11 = t.add_child(child1, 30, 10, 100, 2).unwrap();
+        assert_eq!(t.len(), 4, "should have 4 nodes, got {}", t.len());
+
+        let res = t.remove_child(t.get_top(), 10);
+        assert!(res.is_ok(), "could not remove child");
+        assert_eq!(res.unwrap(), 2, "should have removed child and its sub child");
+        assert_eq!(t.len(), 2, "should have 2 nodes left, got {}", t.len());
+
+        assert!(t.get_child(t.get_top(), 10).unwrap().is_none(), "removed child still reachable via key");
+        assert!(t.get_node(child1).is_err(), "removed child still returned by get_node");
+        assert!(t.get_node(child11).is_err(), "removed sub child still returned by get_node");
+
+        let top = t.get_node(t.get_top()).unwrap();
+        assert_eq!(top.n_children, 1, "top should have 1 child left, got {}", top.n_children);
+
+        let children = t.get_child_iter(t.get_top()).collect::<Vec<(u16, NodeId)>>();
+        assert_eq!(children, vec![(15, 2)], "iterator should only return remaining child");
+
+        let res = t.remove_child(t.get_top(), 10);
+        assert_eq!(res.unwrap(), 0, "removing a non existing key shall remove nothing");
+
+        let child3 = t.add_child(t.get_top(), 10, 300, 3000, 2);
+        assert!(child3.is_ok(), "should be able to add child again after removal");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_prune_subtree() {
+    let path = test_path("can_prune_subtree");
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 10, 100, 1000, 2).unwrap();
+        let child11 = t.add_child(child1, 30, 10, 100, 2).unwrap();
+        let child12 = t.add_child(child1, 35, 10, 100, 2).unwrap();
+        let child121 = t.add_child(child12, 40, 1, 10, 2).unwrap();
+
+        let res = t.prune_subtree(child12);
+        assert!(res.is_ok(), "could not prune subtree");
+        assert_eq!(res.unwrap(), 2, "should have pruned 2 nodes");
+        assert_eq!(t.len(), 3, "should have 3 nodes left, got {}", t.len());
+        assert!(t.get_node(child121).is_err(), "pruned node still returned by get_node");
+
+        let nd = t.get_node(child1).unwrap();
+        assert_eq!(nd.n_children, 1, "should have 1 child left, got {}", nd.n_children);
+        assert!(t.get_child(child1, 30).unwrap().is_some(), "sibling of pruned node lost");
+        assert!(t.get_node(child11).is_ok(), "sibling of pruned node lost");
+
+        assert!(t.prune_subtree(t.get_top()).is_err(), "top node shall not be prunable");
+        assert!(t.prune_subtree(child12).is_err(), "pruning an already pruned node shall fail");
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    let res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(res.is_ok(), "tree not opened");
+
+    if let Ok(ref t) = res {
+        assert_eq!(t.len(), 3, "pruned nodes shall not be counted after reopening, got {}", t.len());
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn reuses_freed_slots() {
+    let path = test_path("reuses_freed_slots");
+    let node_path = format!("{}/treemap.nodes.bin", path);
+    let map_path = format!("{}/treemap.map.bin", path);
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 10, 100, 1000, 2).unwrap();
+        let _child11 = t.add_child(child1, 30, 10, 100, 2).unwrap();
+        let _child12 = t.add_child(child1, 35, 10, 100, 2).unwrap();
+
+        assert_eq!(t.remove_child(t.get_top(), 10).unwrap(), 3, "should have removed 3 nodes");
+        assert_eq!(t.len(), 1, "only top node should be left, got {}", t.len());
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    let mut res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(res.is_ok(), "tree not opened");
+
+    if let Ok(ref mut t) = res {
+        assert_eq!(t.len(), 1, "only top node should be left after reopening, got {}", t.len());
+        let node_len = metadata(&node_path).unwrap().len();
+        let map_len = metadata(&map_path).unwrap().len();
+
+        let child1 = t.add_child(t.get_top(), 15, 100, 1000, 2).unwrap();
+        assert!(child1 > 0 && child1 < 4, "freed node id should be reused, got {}", child1);
+        let child11 = t.add_child(child1, 30, 10, 100, 2).unwrap();
+        let child12 = t.add_child(child1, 35, 10, 100, 2).unwrap();
+        assert!(child11 < 4 && child12 < 4, "freed node ids should be reused, got {} and {}", child11, child12);
+
+        assert_eq!(metadata(&node_path).unwrap().len(), node_len, "node file should not grow when reusing slots");
+        assert_eq!(metadata(&map_path).unwrap().len(), map_len, "map file should not grow when reusing blocks");
+
+        let nd = t.get_node(child12).unwrap();
+        assert_eq!(nd.parent, Some(child1), "reused node should have new parent");
+        assert_eq!(nd.n_children, 0, "reused node should have no children, got {}", nd.n_children);
+        assert_eq!(t.get_child(child1, 35).unwrap().unwrap().node_id, child12, "should find reused node via key");
+
+        let child4 = t.add_child(t.get_top(), 20, 100, 1000, 2).unwrap();
+        assert_eq!(child4, 4, "should append when no free slots are left, got {}", child4);
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_compact() {
+    let path = test_path("can_compact");
+    let dest_path = test_path("can_compact_dest");
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 10, 100, 1000, 2).unwrap();
+        let child2 = t.add_child(t.get_top(), 15, 200, 2000, 2).unwrap();
+        let _child11 = t.add_child(child1, 30, 10, 100, 2).unwrap();
+        let child21 = t.add_child(child2, 35, 20, 200, 2).unwrap();
+        let child211 = t.add_child(child21, 40, 2, 20, 2).unwrap();
+        t.remove_child(t.get_top(), 10).unwrap();
+
+        assert!(t.compact(&path).is_err(), "compacting onto itself shall fail");
+
+        // A log left at the destination by an earlier tree shall not be replayed onto the copy
+        write(format!("{}/treemap.wal.bin", dest_path), wal_buf(&[(0, (HEADER_LENGTH + 8) as u64, 999u64.to_le_bytes().to_vec())], true)).unwrap();
+        let res = t.compact(&dest_path);
+        assert!(res.is_ok(), "could not compact tree");
+
+        let node_ids = res.unwrap();
+        assert_eq!(node_ids.len(), 4, "should map all 4 live nodes, got {}", node_ids.len());
+        assert_eq!(node_ids[&0], 0, "top node shall keep node id 0");
+        assert_eq!(node_ids[&child2], 1, "node ids shall be dense in breadth first order");
+        assert_eq!(node_ids[&child21], 2, "node ids shall be dense in breadth first order");
+        assert_eq!(node_ids[&child211], 3, "node ids shall be dense in breadth first order");
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    let res = TreeMap::new(&dest_path, 3, MustExist, None);
+    assert!(res.is_ok(), "compacted tree not opened");
+
+    if let Ok(ref t) = res {
+        assert_eq!(t.len(), 4, "compacted tree should have 4 nodes, got {}", t.len());
+        assert_eq!(t.get_node(t.get_top()).unwrap().hits, 0, "stale log replayed onto the compacted tree");
+
+        let nd = t.get_child(t.get_top(), 15).unwrap().unwrap();
+        assert_eq!(nd.node_id, 1, "should have node id 1, got {}", nd.node_id);
+        assert_eq!(nd.hits, 200, "should have 200 hits, got {}", nd.hits);
+        assert_eq!(nd.score, 2000, "should have score 2000, got {}", nd.score);
+
+        let nd = t.get_child(1, 35).unwrap().unwrap();
+        assert_eq!(nd.parent, Some(1), "should have node id 1 as parent");
+        let nd = t.get_child(nd.node_id, 40).unwrap().unwrap();
+        assert_eq!(nd.node_id, 3, "should have node id 3, got {}", nd.node_id);
+        assert_eq!(nd.hits, 2, "should have 2 hits, got {}", nd.hits);
+
+        assert!(t.get_child(t.get_top(), 10).unwrap().is_none(), "removed child shall not be compacted");
+    }
+
+    remove_files(res.unwrap(), &dest_path);
+    remove_files(TreeMap::new(&path, 3, MustExist, None).unwrap(), &path);
+}
+
+#[test]
+fn can_reroot() {
+    let path = test_path("can_reroot");
+    let dest_path = test_path("can_reroot_dest");
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let _child1 = t.add_child(t.get_top(), 10, 100, 1000, 2).unwrap();
+        let child2 = t.add_child(t.get_top(), 15, 200, 2000, 2).unwrap();
+        let child21 = t.add_child(child2, 35, 20, 200, 2).unwrap();
+        let _child22 = t.add_child(child2, 40, 30, 300, 2).unwrap();
+        let _child211 = t.add_child(child21, 45, 2, 20, 2).unwrap();
+
+        write(format!("{}/treemap.wal.bin", dest_path), wal_buf(&[(0, (HEADER_LENGTH + 8) as u64, 999u64.to_le_bytes().to_vec())], true)).unwrap();
+        let res = t.reroot(child2, &dest_path);
+        assert!(res.is_ok(), "could not reroot tree");
+
+        let r = res.unwrap();
+        assert_eq!(r.len(), 4, "rerooted tree should have 4 nodes, got {}", r.len());
+
+        let top = r.get_node(r.get_top()).unwrap();
+        assert_eq!(top.hits, 200, "new top should keep its 200 hits, got {}", top.hits);
+        assert_eq!(top.score, 2000, "new top should keep its score 2000, got {}", top.score);
+        assert_eq!(top.n_children, 2, "new top should keep its 2 children, got {}", top.n_children);
+        assert!(top.parent.is_none(), "new top shall have no parent");
+        assert!(r.get_parent(r.get_top()).unwrap().is_none(), "get_parent of new top shall return none");
+
+        let nd = r.get_child(r.get_top(), 35).unwrap().unwrap();
+        assert_eq!(nd.hits, 20, "should have 20 hits, got {}", nd.hits);
+        let nd = r.get_child(nd.node_id, 45).unwrap().unwrap();
+        assert_eq!(nd.hits, 2, "should have 2 hits, got {}", nd.hits);
+        assert!(r.get_child(r.get_top(), 10).unwrap().is_none(), "sibling of new top shall be discarded");
+
+        remove_files(r, &dest_path);
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_reroot_in_place() {
+    let path = test_path("can_reroot_in_place");
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let _child1 = t.add_child(t.get_top(), 10, 100, 1000, 2).unwrap();
+        let child2 = t.add_child(t.get_top(), 15, 200, 2000, 2).unwrap();
+        let child21 = t.add_child(child2, 35, 20, 200, 2).unwrap();
+
+        let res = t.reroot_in_place(child2);
+        assert!(res.is_ok(), "could not reroot tree in place");
+
+        let node_ids = res.unwrap();
+        assert_eq!(node_ids[&child2], 0, "new top shall get node id 0");
+        assert_eq!(t.len(), 2, "rerooted tree should have 2 nodes, got {}", t.len());
+
+        let top = t.get_node(t.get_top()).unwrap();
+        assert_eq!(top.hits, 200, "new top should keep its 200 hits, got {}", top.hits);
+        assert!(top.parent.is_none(), "new top shall have no parent");
+
+        let nd = t.get_child(t.get_top(), 35).unwrap().unwrap();
+        assert_eq!(nd.node_id, node_ids[&child21], "child should have its mapped node id");
+
+        let child3 = t.add_child(t.get_top(), 50, 1, 10, 2);
+        assert!(child3.is_ok(), "should be able to add children after rerooting");
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    let res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(res.is_ok(), "rerooted tree not opened");
+
+    if let Ok(ref t) = res {
+        assert_eq!(t.len(), 3, "rerooted tree should have 3 nodes after reopening, got {}", t.len());
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_set_max_children() {
+    let path = test_path("can_set_max_children");
+    let mut res = TreeMap::new(&path, 1, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let _child1 = t.add_child(t.get_top(), 10, 100, 1000, 2).unwrap();
+        assert!(t.add_child(t.get_top(), 15, 200, 2000, 2).is_err(), "second child shall fail");
+
+        let res = t.set_max_children(t.get_top(), 3);
+        assert!(res.is_ok(), "could not grow max children");
+
+        let child2 = t.add_child(t.get_top(), 15, 200, 2000, 2);
+        assert!(child2.is_ok(), "second child shall go ok after growing");
+        let child3 = t.add_child(t.get_top(), 20, 300, 3000, 2);
+        assert!(child3.is_ok(), "third child shall go ok after growing");
+        assert!(t.add_child(t.get_top(), 25, 400, 4000, 2).is_err(), "fourth child shall fail");
+
+        let top = t.get_node(t.get_top()).unwrap();
+        assert_eq!(top.max_children, 3, "should have max children 3, got {}", top.max_children);
+        assert_eq!(top.n_children, 3, "should have 3 children, got {}", top.n_children);
+        for (key, hits) in [(10, 100), (15, 200), (20, 300)] {
+            let nd = t.get_child(t.get_top(), key).unwrap();
+            assert_eq!(nd.map(|n| n.hits), Some(hits), "child with key {} lost when relocating", key);
+        }
+
+        assert!(t.set_max_children(t.get_top(), 2).is_err(), "shall not shrink below number of children");
+
+        let leaf = child3.unwrap();
+        assert!(t.set_max_children(leaf, 0).is_ok(), "should be able to shrink childless node");
+        assert!(t.add_child(leaf, 30, 1, 10, 2).is_err(), "child shall fail after shrinking to zero");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_get_children_among_many() {
+    let path = test_path("can_get_children_among_many");
+    let mut res = TreeMap::new(&path, 300, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let keys = (0..300u32).map(|k| ((k * 7919) % 1000) as u16).collect::<Vec<u16>>();
+        for &key in &keys {
+            t.add_child(t.get_top(), key, key as u64, 0, 0).unwrap();
+        }
+
+        for &key in &keys {
+            let nd = t.get_child(t.get_top(), key).unwrap();
+            assert_eq!(nd.map(|n| n.hits), Some(key as u64), "could not get child with key {}", key);
+        }
+        assert!(t.get_child(t.get_top(), 1001).unwrap().is_none(), "should not get child for missing key");
+
+        t.remove_child(t.get_top(), keys[150]).unwrap();
+        assert!(t.get_child(t.get_top(), keys[150]).unwrap().is_none(), "removed child still reachable via key");
+        let nd = t.get_child(t.get_top(), keys[151]).unwrap();
+        assert_eq!(nd.map(|n| n.hits), Some(keys[151] as u64), "could not get child after removal");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_read_unsorted_child_maps() {
+    let path = test_path("can_read_unsorted_child_maps");
+
+    // Top node with three children whose child map block is stored in unsorted key order, behind
+    // the headers of a fresh tree
+    drop(TreeMap::new(&path, 4, TruncateCreate, None).unwrap());
+    let mut nodes = read(format!("{}/treemap.nodes.bin", path)).unwrap()[..HEADER_LENGTH].to_vec();
+    let mut map = read(format!("{}/treemap.map.bin", path)).unwrap()[..HEADER_LENGTH].to_vec();
+    let header_length = HEADER_LENGTH as u64;
+    for (parent, hits, n_children, max_children) in [(u64::MAX, 0u64, 3u32, 4u32), (header_length, 20, 0, 2), (header_length, 10, 0, 2), (header_length, 30, 0, 2)] {
+        nodes.extend(parent.to_le_bytes());
+        nodes.extend(hits.to_le_bytes());
+        nodes.extend(0u64.to_le_bytes());
+        nodes.extend(header_length.to_le_bytes());
+        nodes.extend(n_children.to_le_bytes());
+        nodes.extend(max_children.to_le_bytes());
+    }
+    for (node_id, key) in [(1u64, 20u16), (2, 10), (3, 30)] {
+        map.extend((header_length + node_id * 40).to_le_bytes());
+        map.extend(key.to_le_bytes());
+    }
+    map.extend([255u8;10]);
+    write(format!("{}/treemap.nodes.bin", path), nodes).unwrap();
+    write(format!("{}/treemap.map.bin", path), map).unwrap();
+
+    let mut res = TreeMap::new(&path, 4, MustExist, None);
+    assert!(res.is_ok(), "tree not opened");
+
+    if let Ok(ref mut t) = res {
+        for (key, hits) in [(10, 10), (20, 20), (30, 30)] {
+            let nd = t.get_child(t.get_top(), key).unwrap();
+            assert_eq!(nd.map(|n| n.hits), Some(hits), "could not get child with key {} from unsorted map", key);
+        }
+
+        let child4 = t.add_child(t.get_top(), 15, 15, 0, 2);
+        assert!(child4.is_ok(), "could not add child to unsorted map");
+
+        for (key, hits) in [(10, 10), (15, 15), (20, 20), (30, 30)] {
+            let nd = t.get_child(t.get_top(), key).unwrap();
+            assert_eq!(nd.map(|n| n.hits), Some(hits), "could not get child with key {} after rewrite", key);
+        }
+        assert_eq!(t.get_node(t.get_top()).unwrap().n_children, 4, "should have 4 children");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_use_other_key_types() {
+    let path = test_path("can_use_other_key_types");
+
+    let mut res = TreeMap::<u32>::open(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 100_000, 100, 1000, 2).unwrap();
+        let _child2 = t.add_child(t.get_top(), 70_000, 200, 2000, 2).unwrap();
+        let _child11 = t.add_child(child1, u32::MAX, 10, 100, 2).unwrap();
+
+        let nd = t.get_child(t.get_top(), 100_000).unwrap();
+        assert_eq!(nd.map(|n| n.node_id), Some(child1), "could not get child via 32 bit key");
+        let nd = t.get_child(child1, u32::MAX).unwrap();
+        assert_eq!(nd.map(|n| n.hits), Some(10), "could not get child via max 32 bit key");
+
+        let mut keys = t.get_child_iter(t.get_top()).map(|(k, _)| k).collect::<Vec<u32>>();
+        keys.sort();
+        assert_eq!(keys, vec![70_000, 100_000], "iterator should return 32 bit keys");
+
+        let map_len = metadata(format!("{}/treemap.map.bin", path)).unwrap().len();
+        assert_eq!(map_len, (HEADER_LENGTH + 2 * 12 + 3 * 12) as u64, "map records should be 12 bytes wide for 32 bit keys, got {}", map_len);
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    let mut res = TreeMap::<u8>::open(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 255, 100, 1000, 2).unwrap();
+        let nd = t.get_child(t.get_top(), 255).unwrap();
+        assert_eq!(nd.map(|n| n.node_id), Some(child1), "could not get child via 8 bit key");
+
+        let map_len = metadata(format!("{}/treemap.map.bin", path)).unwrap().len();
+        assert_eq!(map_len, (HEADER_LENGTH + 3 * 9) as u64, "map records should be 9 bytes wide for 8 bit keys, got {}", map_len);
+    }
+
+    let t = res.unwrap();
+    drop(t);
+    remove_files(TreeMap::new(&path, 3, TruncateCreate, None).unwrap(), &path);
+}
+
+#[derive(Default, Debug, PartialEq)]
+struct MovePayload {
+    prior: f32,
+    virtual_loss: u32,
+    best_move: u16,
+}
+
+impl Payload for MovePayload {
+    const LENGTH: usize = 10;
+
+    fn encode(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&self.prior.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.virtual_loss.to_le_bytes());
+        buf[8..10].copy_from_slice(&self.best_move.to_le_bytes());
+    }
+
+    fn decode(buf: &[u8]) -> Self {
+        MovePayload {
+            prior: f32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            virtual_loss: u32::from_le_bytes(buf[4..8].try_into().unwrap()),
+            best_move: u16::from_le_bytes(buf[8..10].try_into().unwrap()),
+        }
+    }
+    fn prior(&self) -> Option<f32> {
+        Some(self.prior)
+    }
+}
+
+#[test]
+fn can_store_payloads() {
+    let path = test_path("can_store_payloads");
+
+    let mut res = TreeMap::<u16, MovePayload>::open(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    let payload1 = MovePayload { prior: 0.25, virtual_loss: 3, best_move: 7 };
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child_with_payload(t.get_top(), 1, 100, 1000, 2, payload1).unwrap();
+        let child2 = t.add_child(t.get_top(), 2, 200, 2000, 2).unwrap();
+
+        let nd = t.get_node(child1).unwrap();
+        assert_eq!(nd.payload, MovePayload { prior: 0.25, virtual_loss: 3, best_move: 7 }, "payload not stored with the node");
+        let nd = t.get_node(child2).unwrap();
+        assert_eq!(nd.payload, MovePayload::default(), "a child added without payload should get the default payload");
+
+        t.update_payload(child2, MovePayload { prior: 0.5, virtual_loss: 1, best_move: 9 }).unwrap();
+        t.update_node_add(child2, 1, 10).unwrap();
+        let nd = t.get_child(t.get_top(), 2).unwrap().unwrap();
+        assert_eq!(nd.payload.best_move, 9, "payload not updated");
+        assert_eq!(nd.hits, 201, "updating hits should keep the payload");
+        assert_eq!(nd.payload.prior, 0.5, "updating hits should keep the payload");
+
+        let nodes_len = metadata(format!("{}/treemap.nodes.bin", path)).unwrap().len();
+        assert_eq!(nodes_len, (HEADER_LENGTH + 3 * 50) as u64, "node records should be 40 + 10 bytes wide, got {}", nodes_len);
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    let res = TreeMap::<u16, MovePayload>::open(&path, 3, MustExist, None);
+    assert!(res.is_ok(), "tree not opened");
+    if let Ok(ref t) = res {
+        assert_eq!(t.len(), 3, "should have 3 nodes after reopening, got {}", t.len());
+        let nd = t.get_child(t.get_top(), 1).unwrap().unwrap();
+        assert_eq!(nd.payload.virtual_loss, 3, "payload not persisted");
+    }
+
+    let t = res.unwrap();
+    drop(t);
+    remove_files(TreeMap::new(&path, 3, TruncateCreate, None).unwrap(), &path);
+}
+
+#[test]
+fn can_use_signed_and_float_scores() {
+    let path = test_path("can_use_signed_and_float_scores");
+
+    let mut res = TreeMap::<u16, (), i64>::open(&path, 2, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 1, 1, -5, 2).unwrap();
+        t.update_node_add(child1, 1, -10).unwrap();
+        assert_eq!(t.get_node(child1).unwrap().score, -15, "signed score should go below zero");
+
+        t.update_node_add(child1, 0, i64::MIN).unwrap();
+        assert_eq!(t.get_node(child1).unwrap().score, i64::MIN, "signed score should saturate instead of overflow");
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    let res = TreeMap::<u16, (), f64>::open(&path, 2, MustExist, None);
+    assert!(matches!(res, Err(TreeFileError::IncompatibleFormat {..})), "should not open a tree with another score type than it was created with");
+    let res = TreeMap::new(&path, 2, MustExist, None);
+    assert!(matches!(res, Err(TreeFileError::IncompatibleFormat {..})), "should not open a tree with another score type than it was created with");
+
+    let mut res = TreeMap::<u16, (), f64>::open(&path, 2, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 1, 1, 0.5, 2).unwrap();
+        t.update_node_add(child1, 1, -1.0).unwrap();
+        t.update_node_add(child1, 1, -0.25).unwrap();
+        let nd = t.get_node(child1).unwrap();
+        assert_eq!(nd.score, -0.75, "float score should accumulate below zero, got {}", nd.score);
+        assert_eq!(nd.hits, 3, "should have 3 hits, got {}", nd.hits);
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    let res = TreeMap::<u16, (), f64>::open(&path, 2, MustExist, None);
+    assert!(res.is_ok(), "tree not opened with its own score type");
+    if let Ok(ref t) = res {
+        let nd = t.get_child(t.get_top(), 1).unwrap().unwrap();
+        assert_eq!(nd.score, -0.75, "float score not persisted, got {}", nd.score);
+    }
+
+    let t = res.unwrap();
+    drop(t);
+    remove_files(TreeMap::new(&path, 2, TruncateCreate, None).unwrap(), &path);
+}
+
+#[test]
+fn can_backpropagate() {
+    let path = test_path("can_backpropagate");
+
+    let mut res = TreeMap::<u16, (), i64>::open(&path, 2, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 1, 0, 0, 2).unwrap();
+        let child2 = t.add_child(t.get_top(), 2, 0, 0, 2).unwrap();
+        let child11 = t.add_child(child1, 1, 0, 0, 2).unwrap();
+
+        let n = t.backpropagate(child11, 1, |_| 1, true).unwrap();
+        assert_eq!(n, 3, "should update 3 nodes, got {}", n);
+
+        let n = t.backpropagate(child11, 1, |nd| nd.node_id as i64, false).unwrap();
+        assert_eq!(n, 3, "should update 3 nodes, got {}", n);
+
+        let nd = t.get_node(child11).unwrap();
+        assert_eq!((nd.hits, nd.score), (2, 1 + child11 as i64), "wrong leaf values");
+        let nd = t.get_node(child1).unwrap();
+        assert_eq!((nd.hits, nd.score), (2, -1 + child1 as i64), "sign should be flipped on the leaf's parent");
+        let nd = t.get_node(t.get_top()).unwrap();
+        assert_eq!((nd.hits, nd.score), (2, 1), "sign should be flipped back on the grand parent");
+        let nd = t.get_node(child2).unwrap();
+        assert_eq!((nd.hits, nd.score), (0, 0), "nodes off the path should not be updated");
+
+        let res = t.backpropagate(child11, -3, |_| 0, false);
+        assert!(res.is_err(), "should fail when hits go below zero");
+        assert_eq!(t.get_node(child11).unwrap().hits, 2, "a failing backpropagation should not update any node");
+
+        assert!(t.backpropagate(99, 1, |_| 1, false).is_err(), "should fail for a non existing node");
+    }
+
+    let t = res.unwrap();
+    drop(t);
+    remove_files(TreeMap::new(&path, 2, TruncateCreate, None).unwrap(), &path);
+}
+
+#[test]
+fn can_get_and_insert_paths() {
+    let path = test_path("can_get_and_insert_paths");
+
+    let mut res = TreeMap::new(&path, 2, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 1, 100, 1000, 2).unwrap();
+
+        assert_eq!(t.get_path(&[]).unwrap(), Some(t.get_top()), "empty path should give the top node");
+        assert_eq!(t.get_path(&[1]).unwrap(), Some(child1), "could not get path of length 1");
+        assert_eq!(t.get_path(&[1, 2]).unwrap(), None, "should not find a non existing path");
+        assert_eq!(t.get_path_prefix(&[1, 2, 3]).unwrap(), (child1, 1), "wrong prefix for partly existing path");
+        assert_eq!(t.get_path_prefix(&[2, 2]).unwrap(), (t.get_top(), 0), "wrong prefix for non existing path");
+
+        let leaf = t.insert_path(&[1, 2, 3], 1, 10, 2).unwrap();
+        assert_eq!(t.len(), 4, "should only add the missing nodes, got {} nodes", t.len());
+        assert_eq!(t.get_path(&[1, 2, 3]).unwrap(), Some(leaf), "could not get inserted path");
+        assert_eq!(t.get_path_prefix(&[1, 2, 3]).unwrap(), (leaf, 3), "wrong prefix for existing path");
+
+        let nd = t.get_node(child1).unwrap();
+        assert_eq!((nd.hits, nd.score), (100, 1000), "existing nodes on the path should be kept as is");
+        let nd = t.get_node(leaf).unwrap();
+        assert_eq!((nd.hits, nd.score, nd.max_children), (1, 10, 2), "new nodes should get the given defaults");
+
+        assert_eq!(t.insert_path(&[1, 2, 3], 1, 10, 2).unwrap(), leaf, "inserting an existing path should give its leaf");
+        assert_eq!(t.len(), 4, "inserting an existing path should not add nodes");
+
+        t.add_child(t.get_top(), 2, 0, 0, 0).unwrap();
+        assert!(t.insert_path(&[2, 1], 1, 10, 2).is_err(), "should fail when a node on the path can not take more children");
+
+        assert!(t.insert_path(&[1, 2, 3, 7, 8, 9], 1, 10, 0).is_err(), "should fail when a new node on the path can not take children");
+        assert_eq!(t.len(), 5, "a failing path insert should not add nodes, got {}", t.len());
+        assert!(t.get_child(leaf, 7).unwrap().is_none(), "node of a failing path insert was added");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_get_path_to_node() {
+    let path = test_path("can_get_path_to_node");
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child2 = t.add_child(t.get_top(), 2, 0, 0, 3).unwrap();
+        let _child1 = t.add_child(t.get_top(), 1, 0, 0, 3).unwrap();
+        let child23 = t.add_child(child2, 3, 0, 0, 3).unwrap();
+        let _child21 = t.add_child(child2, 1, 0, 0, 3).unwrap();
+        let leaf = t.insert_path(&[2, 3, 7, 5], 0, 0, 3).unwrap();
+
+        assert_eq!(t.path_to(t.get_top()).unwrap(), Vec::<u16>::new(), "path to top node should be empty");
+        assert_eq!(t.path_to(child2).unwrap(), vec![2], "wrong path to child");
+        assert_eq!(t.path_to(child23).unwrap(), vec![2, 3], "wrong path to sub child");
+        assert_eq!(t.path_to(leaf).unwrap(), vec![2, 3, 7, 5], "wrong path to leaf");
+        assert!(t.path_to(99).is_err(), "should fail for a non existing node");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_traverse_depth_and_breadth_first() {
+    let path = test_path("can_traverse_depth_and_breadth_first");
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        t.insert_path(&[2, 1], 1, 0, 3).unwrap();
+        t.insert_path(&[1, 2, 1], 1, 0, 3).unwrap();
+        t.insert_path(&[1, 1], 0, 0, 3).unwrap();
+
+        let keys = t.dfs(t.get_top())
+            .map(|r| r.map(|(d, k, _)| (d, k)))
+            .collect::<Result<Vec<(usize, Option<u16>)>, _>>().unwrap();
+        assert_eq!(keys, vec![(0, None), (1, Some(1)), (2, Some(1)), (2, Some(2)), (3, Some(1)), (1, Some(2)), (2, Some(1))], "wrong depth first order");
+
+        let keys = t.bfs(t.get_top())
+            .map(|r| r.map(|(d, k, _)| (d, k)))
+            .collect::<Result<Vec<(usize, Option<u16>)>, _>>().unwrap();
+        assert_eq!(keys, vec![(0, None), (1, Some(1)), (1, Some(2)), (2, Some(1)), (2, Some(2)), (2, Some(1)), (3, Some(1))], "wrong breadth first order");
+
+        let n = t.bfs(t.get_top()).max_depth(1).count();
+        assert_eq!(n, 3, "should stop at max depth 1, got {} nodes", n);
+
+        let nodes = t.dfs(t.get_top())
+            .skip_subtree(|nd| nd.n_children == 2 && nd.parent.is_some())
+            .map(|r| r.unwrap().2.node_id)
+            .collect::<Vec<NodeId>>();
+        assert_eq!(nodes.len(), 4, "should skip the subtree below the node with 2 children, got {} nodes", nodes.len());
+
+        let child1 = t.get_path(&[1]).unwrap().unwrap();
+        let n = t.dfs(child1).count();
+        assert_eq!(n, 4, "should traverse from a given start node, got {} nodes", n);
+
+        let res = t.dfs(99).collect::<Vec<_>>();
+        assert!(res.len() == 1 && res[0].is_err(), "should give one error for a non existing start node");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_select_child() {
+    let path = test_path("can_select_child");
+
+    let mut res = TreeMap::<u16, MovePayload, f64>::open(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        assert!(t.select_child(t.get_top(), MaxVisits).unwrap().is_none(), "should select nothing without children");
+
+        t.update_node_add(t.get_top(), 30, 0.0).unwrap();
+        let child1 = t.add_child_with_payload(t.get_top(), 1, 10, 6.0, 2, MovePayload { prior: 0.1, ..Default::default() }).unwrap();
+        let child2 = t.add_child_with_payload(t.get_top(), 2, 20, 10.0, 2, MovePayload { prior: 0.1, ..Default::default() }).unwrap();
+        let child3 = t.add_child_with_payload(t.get_top(), 3, 0, 0.0, 2, MovePayload { prior: 0.8, ..Default::default() }).unwrap();
+
+        let (key, nd) = t.select_child(t.get_top(), MaxVisits).unwrap().unwrap();
+        assert_eq!((key, nd.node_id), (2, child2), "max visits should select the most visited child");
+        let (key, nd) = t.select_child(t.get_top(), MaxMean).unwrap().unwrap();
+        assert_eq!((key, nd.node_id), (1, child1), "max mean should select the child with the best mean score");
+        let (key, nd) = t.select_child(t.get_top(), Uct { exploration: 1.4 }).unwrap().unwrap();
+        assert_eq!((key, nd.node_id), (3, child3), "uct should select an unvisited child first");
+        let (key, nd) = t.select_child(t.get_top(), Puct { exploration: 1.0 }).unwrap().unwrap();
+        assert_eq!((key, nd.node_id), (3, child3), "puct should select the unvisited child with a high prior");
+
+        t.update_payload(child3, MovePayload { prior: 0.0, ..Default::default() }).unwrap();
+        let (key, _) = t.select_child(t.get_top(), Puct { exploration: 1.0 }).unwrap().unwrap();
+        assert_eq!(key, 1, "puct should select the child with the best mean score when priors are equal");
+
+        t.update_node_add(child3, 5, 1.0).unwrap();
+        let (key, _) = t.select_child(t.get_top(), Uct { exploration: 1.4 }).unwrap().unwrap();
+        assert_eq!(key, 1, "uct should select the child with the best upper confidence bound");
+
+        assert!(t.select_child(99, MaxVisits).is_err(), "should fail for a non existing node");
+    }
+
+    let t = res.unwrap();
+    drop(t);
+    remove_files(TreeMap::new(&path, 2, TruncateCreate, None).unwrap(), &path);
+}
+
+#[test]
+fn can_get_principal_variation() {
+    let path = test_path("can_get_principal_variation");
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        t.insert_path(&[1, 1, 1], 5, 5, 3).unwrap();
+        t.insert_path(&[2, 3], 5, 1, 3).unwrap();
+        t.insert_path(&[2, 4], 2, 2, 3).unwrap();
+        t.update_node_add(t.get_path(&[2]).unwrap().unwrap(), 5, 0).unwrap();
+
+        let pv = t.principal_variation(t.get_top(), MaxVisits, 10).unwrap();
+        let keys = pv.iter().map(|(k, _)| *k).collect::<Vec<u16>>();
+        assert_eq!(keys, vec![2, 3], "wrong principal variation by visits");
+        assert_eq!(pv[0].1.hits, 10, "principal variation should carry the node data");
+
+        let keys = t.principal_variation(t.get_top(), MaxMean, 10).unwrap()
+            .into_iter().map(|(k, _)| k).collect::<Vec<u16>>();
+        assert_eq!(keys, vec![1, 1, 1], "wrong principal variation by mean score");
+
+        let pv = t.principal_variation(t.get_top(), MaxMean, 2).unwrap();
+        assert_eq!(pv.len(), 2, "principal variation should stop at max length");
+
+        let child2 = t.get_path(&[2]).unwrap().unwrap();
+        let keys = t.principal_variation(child2, MaxMean, 10).unwrap()
+            .into_iter().map(|(k, _)| k).collect::<Vec<u16>>();
+        assert_eq!(keys, vec![4], "wrong principal variation from a given start node");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_get_nodes_in_batches() {
+    let path = test_path("can_get_nodes_in_batches");
+
+    let mut res = TreeMap::new(&path, 5, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let mut children: Vec<NodeId> = Vec::new();
+        for key in [5, 3, 1, 4, 2] {
+            children.push(t.add_child(t.get_top(), key, key as u64, 10 * key as u64, 2).unwrap());
+        }
+        let child11 = t.add_child(children[2], 1, 100, 1000, 2).unwrap();
+        t.remove_child(t.get_top(), 4).unwrap();
+
+        let nodes = t.get_nodes(&[child11, children[0], 99, children[1], children[3], children[0], t.get_top()]);
+        assert_eq!(nodes.len(), 7, "should return one result per requested node");
+        assert_eq!(nodes[0].as_ref().map(|n| n.hits).ok(), Some(100), "wrong node data for sub child");
+        assert_eq!(nodes[1].as_ref().map(|n| n.node_id).ok(), Some(children[0]), "results should be in requested order");
+        assert!(nodes[2].is_err(), "should fail for a non existing node");
+        assert_eq!(nodes[3].as_ref().map(|n| n.score).ok(), Some(30), "wrong node data for child");
+        assert!(nodes[4].is_err(), "should fail for a removed node");
+        assert_eq!(nodes[5].as_ref().map(|n| n.node_id).ok(), Some(children[0]), "should return repeated nodes");
+        assert_eq!(nodes[6].as_ref().map(|n| n.n_children).ok(), Some(4), "wrong node data for top node");
+
+        let children_data = t.get_children_data(t.get_top()).unwrap();
+        let keys = children_data.iter().map(|(k, nd)| (*k, nd.hits)).collect::<Vec<(u16, u64)>>();
+        assert_eq!(keys, vec![(1, 1), (2, 2), (3, 3), (5, 5)], "wrong children data");
+        assert!(t.get_children_data(child11).unwrap().is_empty(), "should have no children data for a leaf");
+        assert!(t.get_children_data(99).is_err(), "should fail for a non existing node");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn can_apply_write_batch() {
+    let path = test_path("can_apply_write_batch");
+    let node_path = format!("{}/treemap.nodes.bin", path);
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 1, 10, 100, 3).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.add_child(t.get_top(), 2, 1, 10, 3);
+        batch.add_child(child1, 1, 2, 20, 3);
+        batch.update_node_add(child1, 1, 5);
+        batch.update_node_add(t.get_top(), 2, 30);
+        batch.update_node_add(child1, 1, -15);
+        assert_eq!(batch.len(), 5, "batch should hold 5 operations");
+
+        let added = t.apply_batch(batch).unwrap();
+        assert_eq!(added.len(), 2, "should return the ids of the added children");
+        assert_eq!(t.get_child(t.get_top(), 2).unwrap().map(|n| n.node_id), Some(added[0]), "first child not added");
+        assert_eq!(t.get_child(child1, 1).unwrap().map(|n| n.node_id), Some(added[1]), "sub child not added");
+
+        let nd = t.get_node(child1).unwrap();
+        assert_eq!((nd.hits, nd.score, nd.n_children), (12, 90, 1), "updates should be merged and keep the added child");
+        let nd = t.get_node(t.get_top()).unwrap();
+        assert_eq!((nd.hits, nd.score, nd.n_children), (2, 30, 2), "top node not updated");
+
+        let mut batch = WriteBatch::new();
+        batch.update_node_add(child1, 0, -1000);
+        assert!(t.apply_batch(batch).is_err(), "should fail when a score goes below zero");
+        assert_eq!(t.get_node(child1).unwrap().score, 90, "failing updates should not be written");
+
+        let mut batch = WriteBatch::<u16>::new();
+        batch.update_node_add(99, 1, 1);
+        assert!(t.apply_batch(batch).is_err(), "should fail for a non existing node");
+
+        let (n_nodes, nodes) = (t.len(), read(&node_path).unwrap());
+        let mut batch = WriteBatch::new();
+        batch.add_child(t.get_top(), 3, 1, 10, 3);
+        batch.update_node_add(child1, 1, 1);
+        batch.add_child(t.get_top(), 1, 1, 10, 3);
+        assert!(t.apply_batch(batch).is_err(), "should fail for an existing key");
+        assert_eq!(t.len(), n_nodes, "a failing batch should not add nodes, got {}", t.len());
+        assert!(t.get_child(t.get_top(), 3).unwrap().is_none(), "child of a failing batch was added");
+        assert_eq!(t.get_node(child1).unwrap().hits, 12, "updates of a failing batch should not be written");
+        assert!(read(&node_path).unwrap() == nodes, "a failing batch should not write to the node file");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+fn wal_buf(records: &[(u8, u64, Vec<u8>)], valid: bool) -> Vec<u8> {
+    let mut buf: Vec<u8> = Vec::new();
+    buf.extend((records.len() as u32).to_le_bytes());
+    for (file, pos, data) in records {
+        buf.push(*file);
+        buf.extend(pos.to_le_bytes());
+        buf.extend((data.len() as u32).to_le_bytes());
+        buf.extend(data);
+    }
+    let checksum = buf.iter().fold(0xcbf29ce484222325u64, |h, v| (h ^ *v as u64).wrapping_mul(0x100000001b3));
+    buf.extend((if valid {checksum} else {checksum ^ 1}).to_le_bytes());
+    buf
+}
+
+#[test]
+fn can_use_write_ahead_log() {
+    let path = test_path("can_use_write_ahead_log");
+    let wal_path = format!("{}/treemap.wal.bin", path);
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    let mut child1: NodeId = 0;
+    if let Ok(ref mut t) = res {
+        t.set_write_ahead_log(true).unwrap();
+        child1 = t.add_child(t.get_top(), 1, 10, 100, 2).unwrap();
+        let child2 = t.add_child(t.get_top(), 2, 20, 200, 2).unwrap();
+        t.update_node_add(child2, 1, 1).unwrap();
+        assert!(t.add_child(t.get_top(), 1, 1, 1, 2).is_err(), "should fail for an existing key");
+        assert_eq!(t.len(), 3, "a failing add should not add a node, got {} nodes", t.len());
+        assert_eq!(metadata(&wal_path).unwrap().len(), 0, "log should be empty after each operation");
+
+        let nd = t.get_node(child2).unwrap();
+        assert_eq!((nd.hits, nd.score), (21, 201), "update not written through the log");
+        let child3 = t.add_child(t.get_top(), 3, 30, 300, 2).unwrap();
+        assert_eq!(t.get_child(t.get_top(), 3).unwrap().map(|n| n.node_id), Some(child3), "add after failing add not written");
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    // A committed log left by a crash is replayed, a torn one is ignored
+    write(&wal_path, wal_buf(&[(0, (HEADER_LENGTH + child1 * 40 + 8) as u64, 77u64.to_le_bytes().to_vec())], true)).unwrap();
+    let res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(res.is_ok(), "tree not opened");
+    assert_eq!(res.as_ref().unwrap().get_node(child1).unwrap().hits, 77, "committed log not replayed");
+    assert_eq!(metadata(&wal_path).unwrap().len(), 0, "log should be emptied after replay");
+    drop(res);
+
+    write(&wal_path, wal_buf(&[(0, (HEADER_LENGTH + child1 * 40 + 8) as u64, 88u64.to_le_bytes().to_vec())], false)).unwrap();
+    let res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(res.is_ok(), "tree not opened");
+    if let Ok(ref t) = res {
+        assert_eq!(t.get_node(child1).unwrap().hits, 77, "torn log should not be replayed");
+        assert_eq!(t.len(), 4, "should have 4 nodes after reopening, got {}", t.len());
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn keeps_batches_and_paths_atomic_with_write_ahead_log() {
+    let path = test_path("keeps_batches_and_paths_atomic_with_write_ahead_log");
+    let node_path = format!("{}/treemap.nodes.bin", path);
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        t.set_write_ahead_log(true).unwrap();
+        let leaf = t.insert_path(&[1, 2, 3], 1, 10, 2).unwrap();
+        assert_eq!(t.len(), 4, "should have 4 nodes, got {}", t.len());
+        assert_eq!(t.get_path(&[1, 2, 3]).unwrap(), Some(leaf), "path not inserted through the log");
+        assert!(t.verify().unwrap().is_empty(), "tree should be consistent after inserting a path");
+
+        let nodes = read(&node_path).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.add_child(t.get_top(), 5, 1, 10, 2);
+        batch.add_child(t.get_top(), 6, 1, 10, 2);
+        batch.update_node_add(leaf, 0, -1000);
+        assert!(t.apply_batch(batch).is_err(), "should fail when a score goes below zero");
+        assert_eq!(t.len(), 4, "a failing batch should not add nodes, got {}", t.len());
+        assert!(t.get_child(t.get_top(), 5).unwrap().is_none(), "child of a failing batch was added");
+        assert!(read(&node_path).unwrap() == nodes, "a failing batch should not write to the node file");
+
+        assert!(t.insert_path(&[9, 8], 1, 1, 0).is_err(), "should fail when a new node can not take children");
+        assert_eq!(t.len(), 4, "a failing path insert should not add nodes, got {}", t.len());
+        assert!(t.get_child(t.get_top(), 9).unwrap().is_none(), "node of a failing path insert was added");
+
+        let added = t.apply_batch({
+            let mut batch = WriteBatch::new();
+            batch.add_child(t.get_top(), 5, 1, 10, 2);
+            batch.add_child(t.get_top(), 6, 1, 10, 2);
+            batch.update_node_add(leaf, 1, 1);
+            batch
+        }).unwrap();
+        assert_eq!(added.len(), 2, "should return the ids of the added children");
+        assert_eq!(t.get_child(t.get_top(), 6).unwrap().map(|n| n.node_id), Some(added[1]), "child of the batch not added");
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    let res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(res.is_ok(), "tree not opened");
+    if let Ok(ref t) = res {
+        assert_eq!(t.len(), 6, "should have 6 nodes after reopening, got {}", t.len());
+        assert!(t.verify().unwrap().is_empty(), "tree should be consistent after reopening");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn runs_removals_and_rerooting_through_write_ahead_log() {
+    let mut files: Vec<Vec<Vec<u8>>> = Vec::new();
+
+    for (name, with_log) in [("removals_without_log", false), ("removals_with_log", true)] {
+        let path = test_path(name);
+        let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+        assert!(res.is_ok(), "tree not created");
+
+        if let Ok(ref mut t) = res {
+            t.set_write_ahead_log(with_log).unwrap();
+            let child1 = t.add_child(t.get_top(), 1, 10, 100, 3).unwrap();
+            let child2 = t.add_child(t.get_top(), 2, 20, 200, 3).unwrap();
+            t.insert_path(&[1, 1, 1], 1, 1, 2).unwrap();
+            t.insert_path(&[2, 1, 1], 1, 1, 2).unwrap();
+            t.add_child(child1, 2, 1, 1, 2).unwrap();
+
+            assert_eq!(t.remove_child(child1, 1).unwrap(), 2, "should remove the child and its subtree");
+            t.set_max_children(child2, 5).unwrap();
+            assert_eq!(t.prune_subtree(child1).unwrap(), 2, "should prune the node and its child");
+            assert!(t.set_max_children(child2, 0).is_err(), "should fail below the number of children");
+            assert_eq!(t.len(), 4, "should have 4 nodes, got {}", t.len());
+
+            t.reroot_in_place(child2).unwrap();
+            assert_eq!(t.len(), 3, "should have 3 nodes after rerooting in place, got {}", t.len());
+            assert_eq!(t.get_path(&[1, 1]).unwrap().map(|n| t.path_to(n).unwrap()), Some(vec![1, 1]), "path not kept by rerooting");
+            assert!(t.verify().unwrap().is_empty(), "tree should be consistent");
+        }
+        drop(res);
+
+        let tmp_files = read_dir(&path).unwrap().filter(|e| e.as_ref().unwrap().path().extension().is_some_and(|x| x == "tmp")).count();
+        assert_eq!(tmp_files, 0, "rerooting in place should not leave temporary files");
+        files.push(["nodes", "map", "meta"].iter().map(|f| read(format!("{}/treemap.{}.bin", path, f)).unwrap()).collect());
+
+        // A record without data truncates the file when the log is replayed
+        if with_log {
+            let node_length = files[1][0].len() as u64;
+            write(format!("{}/treemap.wal.bin", path), wal_buf(&[(0, node_length - 40, Vec::new())], true)).unwrap();
+            let res = TreeMap::new(&path, 3, MustExist, None);
+            assert!(res.is_ok(), "tree not opened");
+            assert_eq!(metadata(format!("{}/treemap.nodes.bin", path)).unwrap().len(), node_length - 40, "node file not truncated by the log");
+            drop(res);
+        }
+        remove_files(TreeMap::new(&path, 3, TruncateCreate, None).unwrap(), &path);
+    }
+
+    assert!(files[0] == files[1], "files written through the log should match files written directly");
+}
+
+#[test]
+fn can_verify_and_repair() {
+    let path = test_path("can_verify_and_repair");
+    let node_path = format!("{}/treemap.nodes.bin", path);
+    let map_path = format!("{}/treemap.map.bin", path);
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    let (mut child1, mut child2, mut child11) = (0, 0, 0);
+    if let Ok(ref mut t) = res {
+        child1 = t.add_child(t.get_top(), 1, 0, 0, 2).unwrap();
+        child2 = t.add_child(t.get_top(), 2, 0, 0, 2).unwrap();
+        child11 = t.add_child(child1, 1, 0, 0, 2).unwrap();
+        let _child12 = t.add_child(child1, 2, 0, 0, 2).unwrap();
+        t.remove_child(child1, 2).unwrap();
+
+        assert!(t.verify().unwrap().is_empty(), "a fresh tree should be consistent");
+    }
+
+    let t = res.unwrap();
+    drop(t);
+
+    // Partial tail records and a child whose parent link points elsewhere
+    let mut nodes = read(&node_path).unwrap();
+    let child11_pos = HEADER_LENGTH + child11 * 40;
+    nodes[child11_pos..child11_pos + 8].copy_from_slice(&((HEADER_LENGTH + child2 * 40) as u64).to_le_bytes());
+    nodes.extend([1u8;7]);
+    write(&node_path, nodes).unwrap();
+    let mut maps = read(&map_path).unwrap();
+    maps.extend([1u8;3]);
+    write(&map_path, maps).unwrap();
+
+    let mut res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(res.is_ok(), "tree not opened");
+
+    if let Ok(ref mut t) = res {
+        let issues = t.verify().unwrap();
+        assert_eq!(issues, vec![
+            Inconsistency::PartialNodeRecord { file_length: (HEADER_LENGTH + 5 * 40 + 7) as u64 },
+            Inconsistency::PartialMapRecord { file_length: (HEADER_LENGTH + 3 * 10 + 2 * 10 + 3) as u64 },
+            Inconsistency::DanglingChildMap { node: child1, key: 1 },
+            Inconsistency::Orphan { node: child11 },
+        ], "wrong inconsistencies");
+
+        let issues = t.repair().unwrap();
+        assert_eq!(issues.len(), 4, "repair should return what it found, got {:?}", issues);
+        assert_eq!(t.verify().unwrap(), vec![Inconsistency::Orphan { node: child11 }], "only the orphan should remain after repair");
+        assert_eq!(t.get_node(child1).unwrap().n_children, 0, "dangling child map should be dropped");
+        assert_eq!(metadata(&node_path).unwrap().len(), (HEADER_LENGTH + 5 * 40) as u64, "partial node record should be truncated");
+        assert_eq!(metadata(&map_path).unwrap().len(), (HEADER_LENGTH + 3 * 10 + 2 * 10) as u64, "partial map record should be truncated");
+
+        let child13 = t.add_child(child1, 3, 0, 0, 2).unwrap();
+        assert_eq!(t.get_child(child1, 3).unwrap().map(|n| n.node_id), Some(child13), "should add children after repair");
+    }
+
+    remove_files(res.unwrap(), &path);
+}
+
+#[test]
+fn rejects_incompatible_files() {
+    let path = test_path("rejects_incompatible_files");
+    let node_path = format!("{}/treemap.nodes.bin", path);
+    let meta_path = format!("{}/treemap.meta.bin", path);
+
+    let mut res = TreeMap::new(&path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+    if let Ok(ref mut t) = res {
+        t.add_child(t.get_top(), 1, 10, 100, 2).unwrap();
+    }
+    drop(res);
+
+    let res = TreeMap::<u32>::open(&path, 3, MustExist, None);
+    assert!(matches!(res, Err(TreeFileError::IncompatibleFormat {..})), "should not open with another key width");
+    drop(res);
+    let res = TreeMap::<u16, MovePayload>::open(&path, 3, OpenCreate, None);
+    assert!(matches!(res, Err(TreeFileError::IncompatibleFormat {..})), "should not open with another payload");
+    drop(res);
+
+    let meta = read(&meta_path).unwrap();
+    let mut other_score = meta.clone();
+    other_score[26] += 1;
+    write(&meta_path, &other_score).unwrap();
+    let res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(matches!(res, Err(TreeFileError::IncompatibleFormat {..})), "should not open a meta file of another score type");
+    drop(res);
+    write(&meta_path, &meta[HEADER_LENGTH..]).unwrap();
+    let res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(matches!(res, Err(TreeFileError::IncompatibleFormat {..})), "should not open a meta file without header");
+    drop(res);
+    write(&meta_path, &meta).unwrap();
+    let res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(res.is_ok(), "tree not opened with its own meta file");
+    drop(res);
+
+    let mut nodes = read(&node_path).unwrap();
+    nodes[8] = 99;
+    write(&node_path, &nodes).unwrap();
+    let res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(matches!(res, Err(TreeFileError::IncompatibleFormat {..})), "should not open another format version");
+    drop(res);
+
+    write(&node_path, &nodes[HEADER_LENGTH..]).unwrap();
+    let res = TreeMap::new(&path, 3, MustExist, None);
+    assert!(matches!(res, Err(TreeFileError::IncompatibleFormat {..})), "should not open files without header");
+    drop(res);
+
+    remove_files(TreeMap::new(&path, 3, TruncateCreate, None).unwrap(), &path);
+}
+
+fn write_legacy_tree(path: &str, file_prefix: &str, keys: [u16;4]) {
+    // Version 0.3 layout without header, a top node with three children in an unsorted child map
+    // block and a grandchild below the first child
+    let mut nodes: Vec<u8> = Vec::new();
+    for (parent, hits, first_child_pos, n_children, max_children) in [(u64::MAX, 60u64, 0u64, 3u32, 4u32), (0, 20, 40, 1, 2), (0, 10, 0, 0, 2), (0, 30, 0, 0, 2), (40, 5, 0, 0, 2)] {
+        nodes.extend(parent.to_le_bytes());
+        nodes.extend(hits.to_le_bytes());
+        nodes.extend((hits * 2).to_le_bytes());
+        nodes.extend(first_child_pos.to_le_bytes());
+        nodes.extend(n_children.to_le_bytes());
+        nodes.extend(max_children.to_le_bytes());
+    }
+    let mut map: Vec<u8> = Vec::new();
+    for (node_id, key) in [(1u64, keys[0]), (2, keys[1]), (3, keys[2]), (u64::MAX, u16::MAX), (4, keys[3]), (u64::MAX, u16::MAX)] {
+        map.extend(if node_id == u64::MAX {node_id} else {node_id * 40}.to_le_bytes());
+        map.extend(key.to_le_bytes());
+    }
+    write(format!("{}/{}treemap.nodes.bin", path, file_prefix), nodes).unwrap();
+    write(format!("{}/{}treemap.map.bin", path, file_prefix), map).unwrap();
+}
+
+#[test]
+fn migrates_legacy_files() {
+    let path = test_path("migrates_legacy_files");
+    let dest_path = test_path("migrates_legacy_files_dest");
+    write_legacy_tree(&path, "", [20, 10, 30, 5]);
+
+    let res = TreeMap::new(&path, 4, MustExist, None);
+    assert!(matches!(res, Err(TreeFileError::IncompatibleFormat {..})), "legacy files should not open before migration");
+    drop(res);
+
+    assert!(is_legacy_tree_map(&path, None).unwrap(), "legacy files not detected");
+    assert!(migrate_tree_map(&path, None, Some(&dest_path)).unwrap(), "files not migrated to new directory");
+    assert!(is_legacy_tree_map(&path, None).unwrap(), "migrating to a new directory should keep the legacy files");
+    assert!(migrate_tree_map(&path, None, None).unwrap(), "files not migrated in place");
+    assert!(!migrate_tree_map(&path, None, None).unwrap(), "migrated files should not be migrated again");
+
+    for p in [&path, &dest_path] {
+        let mut res = TreeMap::new(p, 4, MustExist, None);
+        assert!(res.is_ok(), "migrated tree not opened");
+
+        if let Ok(ref mut t) = res {
+            assert_eq!(t.len(), 5, "should have 5 nodes after migration, got {}", t.len());
+            assert!(t.verify().unwrap().is_empty(), "migrated tree should be consistent");
+            assert_eq!(t.get_node(t.get_top()).unwrap().max_children, 4, "top node should keep its max children");
+            for (key, hits) in [(10, 10), (20, 20), (30, 30)] {
+                let nd = t.get_child(t.get_top(), key).unwrap();
+                assert_eq!(nd.map(|n| (n.hits, n.score)), Some((hits, hits * 2)), "could not get child with key {} after migration", key);
+            }
+            let leaf = t.get_path(&[20, 5]).unwrap().unwrap();
+            assert_eq!(t.get_node(leaf).unwrap().hits, 5, "could not get grandchild after migration");
+            assert_eq!(t.path_to(leaf).unwrap(), vec![20, 5], "parent links broken by migration");
+
+            let child = t.add_child(t.get_top(), 15, 15, 0, 2).unwrap();
+            assert_eq!(t.get_child(t.get_top(), 15).unwrap().map(|n| n.node_id), Some(child), "could not add child after migration");
+        }
+
+        remove_files(res.unwrap(), p);
+    }
+}
+
+#[test]
+fn can_rerun_interrupted_migration() {
+    let path = test_path("can_rerun_interrupted_migration");
+    let dest_path = test_path("can_rerun_interrupted_migration_dest");
+    let file_path = |path: &str, name: &str| format!("{}/treemap.{}.bin", path, name);
+
+    // After an interrupted migration in place either the node or the map file can still be in the
+    // legacy format
+    for migrated in ["map", "nodes"] {
+        write_legacy_tree(&path, "", [20, 10, 30, 5]);
+        write_legacy_tree(&dest_path, "", [20, 10, 30, 5]);
+        assert!(migrate_tree_map(&dest_path, None, None).unwrap(), "files not migrated in place");
+        copy(file_path(&dest_path, migrated), file_path(&path, migrated)).unwrap();
+
+        assert!(is_legacy_tree_map(&path, None).unwrap(), "partly migrated files not detected with a migrated {} file", migrated);
+        assert!(migrate_tree_map(&path, None, None).unwrap(), "partly migrated files not migrated with a migrated {} file", migrated);
+        assert!(!is_legacy_tree_map(&path, None).unwrap(), "files should be migrated after the rerun");
+
+        let res = TreeMap::new(&path, 4, MustExist, None);
+        assert!(res.is_ok(), "tree not opened after rerunning the migration with a migrated {} file", migrated);
+        if let Ok(ref t) = res {
+            assert_eq!(t.len(), 5, "should have 5 nodes after migration, got {}", t.len());
+            assert!(t.verify().unwrap().is_empty(), "tree should be consistent after rerunning the migration");
+            assert_eq!(t.get_path(&[20, 5]).unwrap().map(|n| t.path_to(n).unwrap()), Some(vec![20, 5]), "could not get grandchild after migration");
+        }
+
+        remove_files(res.unwrap(), &path);
+        remove_files(TreeMap::new(&dest_path, 4, MustExist, None).unwrap(), &dest_path);
+    }
+}
+
+fn run_storage_workload<St: Storage>(path: &str) {
+    let mut res = TreeMap::<u16, (), u64, St>::open(path, 3, TruncateCreate, None);
+    assert!(res.is_ok(), "tree not created");
+
+    if let Ok(ref mut t) = res {
+        let child1 = t.add_child(t.get_top(), 1, 10, 100, 3).unwrap();
+        let child2 = t.add_child(t.get_top(), 2, 20, 200, 2).unwrap();
+        let leaf = t.insert_path(&[1, 5, 7], 1, 1, 2).unwrap();
+        t.add_child(child1, 6, 1, 1, 2).unwrap();
+        t.add_child(child1, 8, 1, 1, 2).unwrap();
+        t.update_node_add(child2, 2, 20).unwrap();
+        t.backpropagate(leaf, 1, |_| 1, false).unwrap();
+        t.remove_child(child1, 6).unwrap();
+        t.add_child(child2, 3, 3, 3, 2).unwrap();
+
+        assert_eq!(t.len(), 7, "should have 7 nodes, got {}", t.len());
+        assert_eq!(t.get_path(&[1, 5, 7]).unwrap(), Some(leaf), "could not follow path");
+        assert_eq!(t.get_node(child1).unwrap().hits, 11, "backpropagation not stored");
+        assert_eq!(t.get_child(t.get_top(), 2).unwrap().map(|n| n.hits), Some(22), "update not stored");
+        assert!(t.verify().unwrap().is_empty(), "tree should be consistent");
+    }
+}
+
+#[test]
+fn mmap_storage_matches_file_storage() {
+    let mut files: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+
+    for name in ["mmap_storage_file", "mmap_storage_mmap"] {
+        let path = test_path(name);
+        if name == "mmap_storage_file" {
+            run_storage_workload::<FileStorage>(&path);
+        } else {
+            run_storage_workload::<MmapStorage>(&path);
+        }
+
+        let node_path = format!("{}/treemap.nodes.bin", path);
+        let mut res = TreeMap::<u16, (), u64, MmapStorage>::open(&path, 3, MustExist, None);
+        assert!(res.is_ok(), "tree not opened");
+        if let Ok(ref mut t) = res {
+            assert_eq!(t.len(), 7, "should have 7 nodes after reopening, got {}", t.len());
+            assert_eq!(t.get_child(t.get_top(), 2).unwrap().map(|n| n.hits), Some(22), "update not persisted");
+
+            // Committing through the write ahead log syncs the files, which must end at the stored length
+            t.set_write_ahead_log(true).unwrap();
+            t.add_child(t.get_top(), 3, 1, 1, 2).unwrap();
+        }
+        let synced_length = metadata(&node_path).unwrap().len();
+        drop(res);
+        assert_eq!(metadata(&node_path).unwrap().len(), synced_length, "synced node file should not be longer than the stored nodes");
+
+        files.push((read(format!("{}/treemap.nodes.bin", path)).unwrap(), read(format!("{}/treemap.map.bin", path)).unwrap()));
+        remove_files(TreeMap::new(&path, 3, TruncateCreate, None).unwrap(), &path);
+    }
+
+    assert!(files[0] == files[1], "memory mapped files should be identical to plain files");
+}
+
+#[test]
+fn keeps_mmap_files_at_stored_length() {
+    let path = test_path("keeps_mmap_files_at_stored_length");
+
+    let mut t = TreeMap::<u16, (), u64, MmapStorage>::open(&path, 3, TruncateCreate, None).unwrap();
+    for key in 1..=3 {
+        t.add_child(t.get_top(), key, 1, 1, 2).unwrap();
+    }
+    // A tree that is never dropped, as after a crash, must leave files that open as they are
+    std::mem::forget(t);
+
+    let res = TreeMap::<u16, (), u64, MmapStorage>::open(&path, 3, MustExist, None);
+    assert!(res.is_ok(), "tree not opened");
+    if let Ok(ref t) = res {
+        assert_eq!(t.len(), 4, "should have 4 nodes after reopening, got {}", t.len());
+        assert!(t.verify().unwrap().is_empty(), "tree should be consistent after reopening");
+    }
+    drop(res);
+
+    remove_files(TreeMap::new(&path, 3, TruncateCreate, None).unwrap(), &path);
+}
+
+#[test]
+fn can_keep_tree_in_memory() {
+    let path = format!("{}/can_keep_tree_in_memory", MAP_PATH);
+    run_storage_workload::<MemStorage>(&path);
+
+    let res = TreeMap::<u16, (), u64, MemStorage>::open(&path, 3, MustExist, None);
+    assert!(matches!(res, Err(TreeFileError::NonExistingFiles)), "in memory trees should not outlive the tree map");
+
+    let mut t = TreeMap::<u16, (), u64, MemStorage>::open(&path, 3, OpenCreate, None).unwrap();
+    assert!(t.set_write_ahead_log(true).is_err(), "in memory trees should not use a write ahead log");
+    assert!(matches!(t.compact(&path), Err(TreeFileError::LogicError {..})), "in memory trees should not be compacted to a path");
+    let child1 = t.add_child(t.get_top(), 1, 10, 100, 2).unwrap();
+    let child11 = t.add_child(child1, 1, 5, 50, 2).unwrap();
+    t.add_child(t.get_top(), 2, 20, 200, 2).unwrap();
+
+    let rerooted = t.reroot(child1, &path).unwrap();
+    assert_eq!(rerooted.len(), 2, "rerooted tree should have 2 nodes, got {}", rerooted.len());
+    let node_ids = t.reroot_in_place(child1).unwrap();
+    assert_eq!(t.len(), 2, "should have 2 nodes after rerooting in place, got {}", t.len());
+    assert_eq!(t.get_child(t.get_top(), 1).unwrap().map(|n| n.node_id), node_ids.get(&child11).copied(), "child not kept by rerooting");
+
+    assert!(!std::path::Path::new(&path).exists(), "in memory trees should not touch disk");
+}
+
+#[test]
+fn can_save_and_load_in_memory_tree() {
+    let path = test_path("can_save_and_load_in_memory_tree");
+    let mut t = MemTreeMap::<u16>::open(&path, 3, TruncateCreate, None).unwrap();
+    let child1 = t.add_child(t.get_top(), 1, 10, 100, 3).unwrap();
+    let child11 = t.add_child(child1, 1, 5, 50, 2).unwrap();
+    let child2 = t.add_child(t.get_top(), 2, 20, 200, 2).unwrap();
+    t.update_node_add(child11, 1, 10).unwrap();
+    t.save_to(&path).unwrap();
+
+    let saved = TreeMap::new(&path, 3, MustExist, None).unwrap();
+    assert_eq!(saved.len(), 4, "saved tree should have 4 nodes, got {}", saved.len());
+    assert!(saved.verify().unwrap().is_empty(), "saved tree should be consistent");
+    let node = saved.get_node(child11).unwrap();
+    assert_eq!((node.hits, node.score), (6, 60), "saved node has wrong data: hits {}, score {}", node.hits, node.score);
+    let children: Vec<(u16, NodeId)> = saved.get_child_iter(saved.get_top()).collect();
+    assert_eq!(children.len(), 2, "top should have 2 children, got {}", children.len());
+    assert!(children.contains(&(1, child1)) && children.contains(&(2, child2)), "saved tree has wrong children");
+    drop(saved);
+
+    let mut loaded = MemTreeMap::<u16>::load_from(&path, None).unwrap();
+    assert_eq!(loaded.get_child(child1, 1).unwrap().map(|n| n.node_id), Some(child11), "loaded tree lost a child");
+    loaded.add_child(child2, 3, 1, 1, 2).unwrap();
+    drop(loaded);
+
+    let saved = TreeMap::new(&path, 3, MustExist, None).unwrap();
+    assert_eq!(saved.len(), 4, "changes to a loaded tree should not reach disk");
+    remove_files(saved, &path);
+}